@@ -0,0 +1,193 @@
+use super::logs_api;
+use super::retry::RetryPolicy;
+use crate::handler::{DeadLetter, Handler};
+use crate::models::LogQueue;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+const MAX_ITEMS_DEFAULT: usize = 1000;
+const MAX_AGE_DEFAULT: u64 = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When the background flusher ships the queue without being signalled.
+#[derive(Debug, Clone)]
+pub struct FlushConfig {
+    pub max_items: usize,
+    /// How long logs may wait in the queue.
+    pub max_age: Duration,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        FlushConfig {
+            max_items: match env::var("WOODCHUCK_FLUSH_MAX_ITEMS") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => MAX_ITEMS_DEFAULT,
+            },
+            max_age: Duration::from_millis(match env::var("WOODCHUCK_FLUSH_MAX_AGE") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => MAX_AGE_DEFAULT,
+            }),
+        }
+    }
+}
+
+/// The running background flusher.
+pub struct Flusher {
+    stop: Arc<Notify>,
+    handle: JoinHandle<()>,
+}
+
+impl Flusher {
+    /// Stops the flusher and waits for it, letting a send it is in the middle of finish first so
+    /// it does not race shutdown for the queue.
+    pub async fn stop(self) {
+        self.stop.notify_one();
+        if let Err(e) = self.handle.await {
+            log::error!("{}", e);
+        }
+    }
+}
+
+/// Ships the queue in the background whenever `signal` is notified, such as after
+/// `platform.runtimeDone`, or once it holds `max_items` logs or has held logs for `max_age`, so
+/// sending never holds up the next event. Logs the destination is holding on to are sent once
+/// they have been held too long.
+pub fn start_flusher(
+    config: FlushConfig,
+    log_queue: LogQueue,
+    log_dest: Handler,
+    retry_policy: RetryPolicy,
+    dead_letter: Option<Arc<DeadLetter>>,
+    signal: Arc<Notify>,
+) -> Flusher {
+    async fn run(
+        config: FlushConfig,
+        log_queue: LogQueue,
+        log_dest: Handler,
+        retry_policy: RetryPolicy,
+        dead_letter: Option<Arc<DeadLetter>>,
+        signal: Arc<Notify>,
+        stop: Arc<Notify>,
+    ) {
+        let mut waiting_since: Option<Instant> = None;
+        loop {
+            let signalled = tokio::select! {
+                biased;
+                _ = stop.notified() => return,
+                _ = signal.notified() => true,
+                _ = tokio::time::sleep(POLL_INTERVAL) => false,
+            };
+
+            if let Err(failed) = log_dest.read().await.flush_expired().await {
+                println!(
+                    "failed to send {} held logs, appending back to queue",
+                    failed.len()
+                );
+                let reason = failed.reason.as_deref();
+                logs_api::reject(failed.rejected, reason, 1, dead_letter.as_deref()).await;
+                log_queue.write().await.extend(failed.logs);
+            }
+
+            let length = log_queue.read().await.len();
+            if length == 0 {
+                waiting_since = None;
+                continue;
+            }
+            let waiting = waiting_since.get_or_insert_with(Instant::now).elapsed();
+
+            if signalled || length >= config.max_items || waiting >= config.max_age {
+                log::debug!("Flushing {} logs", length);
+                logs_api::consume_retry(
+                    &log_queue,
+                    &log_dest,
+                    &retry_policy,
+                    dead_letter.as_deref(),
+                )
+                .await;
+                waiting_since = None;
+            }
+        }
+    }
+    let stop = Arc::new(Notify::new());
+    let handle = tokio::spawn(run(
+        config,
+        log_queue,
+        log_dest,
+        retry_policy,
+        dead_letter,
+        signal,
+        stop.clone(),
+    ));
+    Flusher { stop, handle }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start_flusher, FlushConfig};
+    use crate::extension::retry::RetryPolicy;
+    use crate::models::{new_log_queue, Log};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn flushes_when_signalled() {
+        //Arrange
+        let queue = new_log_queue();
+        let dest = crate::handler::get_default().unwrap();
+        let signal = Arc::new(Notify::new());
+        let config = FlushConfig {
+            max_items: 100,
+            max_age: Duration::from_secs(60),
+        };
+        start_flusher(
+            config,
+            queue.clone(),
+            dest,
+            RetryPolicy::default(),
+            None,
+            signal.clone(),
+        );
+        queue.write().await.push(Log::Formatted(
+            serde_json::json!({ "message": "Hello World" }),
+        ));
+
+        //Act
+        signal.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        //Assert
+        assert_eq!(queue.read().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn stops_when_asked() {
+        //Arrange
+        let queue = new_log_queue();
+        let dest = crate::handler::get_default().unwrap();
+        let signal = Arc::new(Notify::new());
+        let flusher = start_flusher(
+            FlushConfig::default(),
+            queue.clone(),
+            dest,
+            RetryPolicy::default(),
+            None,
+            signal.clone(),
+        );
+
+        //Act
+        flusher.stop().await;
+        queue.write().await.push(Log::Formatted(
+            serde_json::json!({ "message": "Hello World" }),
+        ));
+        signal.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        //Assert
+        assert_eq!(queue.read().await.len(), 1);
+    }
+}
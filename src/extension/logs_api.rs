@@ -1,9 +1,11 @@
 use super::{base_url,ExtensionId, EXTENSION_ID_HEADER};
-use crate::models::{LogQueue, RawCloudWatchLog};
-use crate::handler::{Handler, FailedToSendLogsError};
+use super::retry::RetryPolicy;
+use crate::models::{Log, LogQueue, RawCloudWatchLog};
+use crate::handler::{DeadLetter, FailedToSendLogsError, Handler, LogHandlerResponse};
 use reqwest::Client;
 use warp::{path, serve, Filter, Reply};
-use std::{env, thread::sleep, time::Duration};
+use std::{env, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::Notify;
 use crate::parser::parse;
 
 const MAX_ITEMS_DEFAULT: u32 = 1000;
@@ -48,10 +50,18 @@ impl Default for LogSubscriptionConfig {
     }
 }
 
+impl LogSubscriptionConfig {
+    /// How long the Logs API may hold logs before delivering them.
+    pub fn buffer_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout as u64)
+    }
+}
+
 fn log_subscription_request(config: &LogSubscriptionConfig) -> serde_json::Value {
     serde_json::from_str(
         format!(
             "{{ 
+                \"schemaVersion\": \"2021-03-18\",
                 \"destination\": 
                 {{ 
                     \"protocol\": \"HTTP\", 
@@ -59,6 +69,7 @@ fn log_subscription_request(config: &LogSubscriptionConfig) -> serde_json::Value
                 }},
                 \"types\": 
                 [
+                    \"platform\",
                     \"function\"
                 ],
                 \"buffering\": 
@@ -84,16 +95,18 @@ pub async fn subscribe(config: &LogSubscriptionConfig,client: &Client, ext_id: &
         .unwrap();
 }
 
-pub fn start_log_server(config: &LogSubscriptionConfig, log_queue: LogQueue) {
-    async fn run(port: u16, log_queue: LogQueue) {
+/// Logs are added to `log_queue`, and `flush_signal` is notified when the runtime finishes an invocation.
+pub fn start_log_server(config: &LogSubscriptionConfig, log_queue: LogQueue, flush_signal: Arc<Notify>) {
+    async fn run(port: u16, log_queue: LogQueue, flush_signal: Arc<Notify>) {
         let routes = path::end()
             .and(warp::post())
             .and(warp::body::json())
             .and(with_log_queue(log_queue))
+            .and(with_flush_signal(flush_signal))
             .and_then(handle_log);
         serve(routes).run(([0, 0, 0, 0], port)).await;
     }
-    tokio::spawn(run(config.port, log_queue));
+    tokio::spawn(run(config.port, log_queue, flush_signal));
 }
 
 fn with_log_queue(
@@ -102,49 +115,108 @@ fn with_log_queue(
     warp::any().map(move || log_queue.clone())
 }
 
-pub async fn consume_retry(queue: &LogQueue, dest:&Handler, attempts: u64, sleep_ms: u64) {
-    for _ in 0..attempts {
-        if consume(queue, dest).await == true {
-            break;
+fn with_flush_signal(
+    flush_signal: Arc<Notify>,
+) -> impl Filter<Extract = (Arc<Notify>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || flush_signal.clone())
+}
+
+/// Retries sending the queue until it is empty, backing off between attempts until the policy's
+/// attempts or time budget run out. Logs the destination rejected, and those still failing when
+/// retrying is given up, go to the dead letter when there is one.
+pub async fn consume_retry(queue: &LogQueue, dest:&Handler, policy: &RetryPolicy, dead_letter: Option<&DeadLetter>) {
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let FailedToSendLogsError{logs, rejected, reason} = match consume(queue, dest).await {
+            Ok(_) => return,
+            Err(failed) => failed,
+        };
+        reject(rejected, reason.as_deref(), attempt, dead_letter).await;
+
+        let delay = policy.delay(attempt - 1);
+        let exhausted = attempt >= policy.max_attempts
+            || policy.budget.is_some_and(|budget| started.elapsed() + delay >= budget);
+        match (exhausted, dead_letter) {
+            (true, Some(dead_letter)) => {
+                println!("giving up on {} logs after {} attempts", logs.len(), attempt);
+                let failed = dead_letter.send(&logs, reason.as_deref(), attempt).await;
+                if !failed.is_empty() {
+                    println!("failed to dead letter {}, appending back to queue",failed.len());
+                    queue.write().await.extend(failed);
+                }
+                return;
+            },
+            _ => {
+                println!("failed to send {}, appending back to queue",logs.len());
+                queue.write().await.extend(logs);
+            },
+        }
+        if exhausted || queue.read().await.is_empty() {
+            return;
         }
-        sleep(Duration::from_millis(sleep_ms));
+        tokio::time::sleep(delay).await;
     }
 }
 
-pub async fn consume(queue: &LogQueue, dest:&Handler) -> bool {
-    let length = queue.read().await.len();
-    match length
+/// Sends logs the destination rejected to the dead letter, they are dropped when there is none or
+/// it fails too as sending them again would not help.
+pub async fn reject(rejected: Vec<Log>, reason: Option<&str>, attempts: u32, dead_letter: Option<&DeadLetter>) {
+    let dropped = match dead_letter {
+        Some(dead_letter) => dead_letter.send(&rejected, reason, attempts).await,
+        None => rejected,
+    };
+    if !dropped.is_empty() {
+        println!("dropping {} logs rejected by the destination",dropped.len());
+    }
+}
+
+/// Sends everything in the queue, handing back whatever failed.
+pub async fn consume(queue: &LogQueue, dest:&Handler) -> LogHandlerResponse {
+    let split = queue.write().await.split_off(0);
+    match split.len()
     {
-        0 => false,
-        _ => {
-            let split = queue.write().await.split_off(0);
-            match dest.read().await.handle_logs(split).await {
-                Ok(_) => true,
-                Err(FailedToSendLogsError{logs}) => {
-                    println!("failed to send {}, appending back to queue",logs.len());
-                    queue.write().await.extend(logs);
-                    false
-                },
-            }
-        }
+        0 => Ok(()),
+        _ => dest.read().await.handle_logs(split).await,
     }
 }
 
 async fn handle_log(
     logs: Vec<RawCloudWatchLog>,
     log_queue: LogQueue,
+    flush_signal: Arc<Notify>,
 ) -> Result<impl Reply, std::convert::Infallible> {
     log::debug!("Adding {} logs", logs.len());
+    let runtime_done = logs.iter().any(|log| log.r#type == "platform.runtimeDone");
     log_queue.write().await.append(&mut parse(logs.clone()));
     log::debug!("Added {} logs", logs.len());
+    if runtime_done {
+        flush_signal.notify_one();
+    }
     Ok(warp::reply())
 }
 
 #[cfg(test)]
 mod tests {
     use super::RawCloudWatchLog;
-    use crate::models::new_log_queue;
-    use super::{consume, handle_log};
+    use crate::extension::retry::RetryPolicy;
+    use crate::handler::{DeadLetter, LogHandler, LogHandlerResponse};
+    use crate::models::{new_log_queue, Log};
+    use super::{consume, consume_retry, handle_log};
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use tokio::sync::{Notify, RwLock};
+
+    struct Failing;
+
+    #[async_trait]
+    impl LogHandler for Failing {
+        async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+            Err(logs.into())
+        }
+    }
+
     #[tokio::test]
     async fn consume_log() {
         //Arrange
@@ -155,7 +227,7 @@ mod tests {
                 record:
             serde_json::Value::String("2020-11-18T23:52:30.128Z\t6e48723a-1596-4313-a9af-e4da9214d637\tINFO\tHello World\n".to_string())
                 , ..Default::default()
-            }], queue.clone()
+            }], queue.clone(), Arc::new(Notify::new())
         ).await;
 
         match rslt {
@@ -163,9 +235,26 @@ mod tests {
             Err(e) => assert!(false, e),
         };
         //Act
-        consume(&queue,&dest).await;
+        let rslt = consume(&queue,&dest).await;
 
         //Assert
+        assert!(rslt.is_ok());
         assert_eq!(queue.read().await.len(), 0);
     }
+
+    #[tokio::test]
+    async fn requeues_logs_the_dead_letter_refuses() {
+        //Arrange
+        let queue = new_log_queue();
+        queue.write().await.push(Log::Formatted(serde_json::json!({ "message": "Hello World" })));
+        let dest: crate::handler::Handler = Arc::new(RwLock::new(Failing));
+        let dead_letter = DeadLetter::new("failing".to_string(), Box::new(Failing));
+        let policy = RetryPolicy { max_attempts: 1, ..RetryPolicy::default() };
+
+        //Act
+        consume_retry(&queue, &dest, &policy, Some(&dead_letter)).await;
+
+        //Assert
+        assert_eq!(queue.read().await.len(), 1);
+    }
 }
@@ -5,7 +5,9 @@ use std::collections::HashMap;
 
 pub type ExtensionId = String;
 
+pub mod flusher;
 pub mod logs_api;
+pub mod retry;
 pub mod runtime;
 
 pub const EXTENSION_NAME: &str = "woodchuck";
@@ -24,25 +26,10 @@ cfg_if::cfg_if! {
     }
 }
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "loggly")] {
-        const TARGET_DESTINATION: Option<&str> = Some("loggly");
-    }
-    else if #[cfg(feature = "logzio")] {
-        const TARGET_DESTINATION: Option<&str> = Some("logzio");
-    }
-    else if #[cfg(feature = "firehose")] {
-        const TARGET_DESTINATION: Option<&str> = Some("firehose");
-    }
-    else {
-        const TARGET_DESTINATION: Option<&str> = None;
-    }
-}
-
 pub fn get_extension_name() -> String {
-    let name = match (TARGET_ARCHITECTURE, TARGET_DESTINATION) {
-        (Some(arch), Some(dest)) => format!("{}_{}_{}", EXTENSION_NAME, dest, arch),
-        (_, _) => EXTENSION_NAME.to_string(),
+    let name = match TARGET_ARCHITECTURE {
+        Some(arch) => format!("{}_{}", EXTENSION_NAME, arch),
+        None => EXTENSION_NAME.to_string(),
     };
     cfg_if::cfg_if! {
         if #[cfg(feature = "dev")] {
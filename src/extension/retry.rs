@@ -0,0 +1,89 @@
+use rand::Rng;
+use std::env;
+use std::time::Duration;
+
+const MAX_ATTEMPTS_DEFAULT: u32 = 5;
+const BASE_DELAY_DEFAULT: u64 = 50;
+const MAX_DELAY_DEFAULT: u64 = 1000;
+
+/// How sending the queue is retried when the destination fails.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+    /// The total time allowed across all attempts, unlimited when `None`.
+    pub budget: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: match env::var("WOODCHUCK_RETRY_MAX_ATTEMPTS") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => MAX_ATTEMPTS_DEFAULT,
+            },
+            base_delay: Duration::from_millis(match env::var("WOODCHUCK_RETRY_BASE_DELAY") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => BASE_DELAY_DEFAULT,
+            }),
+            max_delay: Duration::from_millis(match env::var("WOODCHUCK_RETRY_MAX_DELAY") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => MAX_DELAY_DEFAULT,
+            }),
+            jitter: match env::var("WOODCHUCK_RETRY_JITTER") {
+                Ok(var) => var.parse().unwrap(),
+                Err(_) => true,
+            },
+            budget: env::var("WOODCHUCK_RETRY_BUDGET")
+                .ok()
+                .map(|var| Duration::from_millis(var.parse().unwrap())),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying after `attempt` failed attempts, doubling from `base_delay` up
+    /// to `max_delay`. With jitter the delay is picked at random below that.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        match self.jitter {
+            true => {
+                Duration::from_millis(rand::thread_rng().gen_range(0..=delay.as_millis() as u64))
+            }
+            false => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn backs_off_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(1000),
+            jitter: false,
+            budget: None,
+        };
+
+        assert_eq!(policy.delay(0), Duration::from_millis(50));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(40), Duration::from_millis(1000));
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        assert!((0..20).all(|_| jittered.delay(2) <= Duration::from_millis(200)));
+    }
+}
@@ -1,14 +1,28 @@
+use super::flusher::Flusher;
+use super::retry::RetryPolicy;
 use super::{base_url, logs_api, ExtensionId, EXTENSION_ID_HEADER};
-use crate::handler::Handler;
+use crate::handler::{DeadLetter, Handler};
 use crate::models::LogQueue;
 use anyhow::Result;
 use reqwest::Client;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
 
+const DEADLINE_MARGIN_DEFAULT: u64 = 200;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     client: &Client,
     ext_id: ExtensionId,
     log_queue: LogQueue,
     log_dest: Handler,
+    retry_policy: RetryPolicy,
+    buffer_timeout: Duration,
+    flush_signal: Arc<Notify>,
+    flusher: Flusher,
+    dead_letter: Option<Arc<DeadLetter>>,
 ) -> Result<()> {
     loop {
         let event = next_event(&client, &ext_id).await;
@@ -17,24 +31,109 @@ pub async fn run(
             Ok(evt) => match evt {
                 NextEventResponse::Invoke { request_id, .. } => {
                     log::debug!("Request Id: {:?}", request_id);
-                    logs_api::consume(&log_queue, &log_dest).await;
+                    flush_signal.notify_one();
                 }
                 NextEventResponse::Shutdown {
-                    shutdown_reason, ..
+                    shutdown_reason,
+                    deadline_ms,
                 } => {
                     log::debug!("Exiting: {:?}", shutdown_reason);
-                    logs_api::consume_retry(&log_queue, &log_dest, 5, 50).await;
+                    flusher.stop().await;
+                    shutdown(
+                        &log_queue,
+                        &log_dest,
+                        &retry_policy,
+                        dead_letter.as_deref(),
+                        buffer_timeout,
+                        shutdown_deadline(deadline_ms),
+                    )
+                    .await;
                     return Ok(());
                 }
             },
             Err(err) => {
                 log::debug!("Error: {:?}", err);
-                logs_api::consume(&log_queue, &log_dest).await;
+                flush_signal.notify_one();
             }
         }
     }
 }
 
+/// When shutting down has to be finished by, leaving `WOODCHUCK_SHUTDOWN_MARGIN` milliseconds
+/// before the deadline Lambda gives in milliseconds since the epoch.
+fn shutdown_deadline(deadline_ms: u64) -> Instant {
+    let margin = match std::env::var("WOODCHUCK_SHUTDOWN_MARGIN") {
+        Ok(var) => var.parse().unwrap(),
+        Err(_) => DEADLINE_MARGIN_DEFAULT,
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64);
+    Instant::now() + Duration::from_millis(deadline_ms.saturating_sub(now_ms + margin))
+}
+
+/// Keeps sending and flushing until nothing is pending or the deadline is reached. The Logs API
+/// delivers what it is still buffering after the shutdown event, so the queue is only considered
+/// done once no logs have arrived for `buffer_timeout`.
+async fn shutdown(
+    log_queue: &LogQueue,
+    log_dest: &Handler,
+    retry_policy: &RetryPolicy,
+    dead_letter: Option<&DeadLetter>,
+    buffer_timeout: Duration,
+    deadline: Instant,
+) {
+    let mut flushed = false;
+    let mut flush_attempts = 0;
+    let mut quiet_since = Instant::now();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            break;
+        }
+        if !log_queue.read().await.is_empty() {
+            let policy = RetryPolicy {
+                max_attempts: u32::MAX,
+                budget: Some(remaining),
+                ..retry_policy.clone()
+            };
+            logs_api::consume_retry(log_queue, log_dest, &policy, dead_letter).await;
+            flushed = false;
+            quiet_since = Instant::now();
+            continue;
+        }
+        if !flushed {
+            match log_dest.read().await.flush().await {
+                Ok(_) => flushed = true,
+                Err(failed) => {
+                    println!("failed to flush {} logs, retrying", failed.len());
+                    let reason = failed.reason.as_deref();
+                    logs_api::reject(failed.rejected, reason, flush_attempts + 1, dead_letter)
+                        .await;
+                    log_queue.write().await.extend(failed.logs);
+                    tokio::time::sleep(retry_policy.delay(flush_attempts).min(remaining)).await;
+                    flush_attempts += 1;
+                }
+            }
+            continue;
+        }
+        if quiet_since.elapsed() >= buffer_timeout {
+            return;
+        }
+        tokio::time::sleep(POLL_INTERVAL.min(remaining)).await;
+    }
+
+    let lost = log_queue.read().await.len();
+    match (lost, flushed) {
+        (0, true) => log::debug!("Shutdown deadline reached with nothing pending"),
+        (_, true) => println!("shutdown deadline reached, {} logs lost", lost),
+        (_, false) => println!(
+            "shutdown deadline reached, {} logs lost and the destination was not flushed",
+            lost
+        ),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Tracing {
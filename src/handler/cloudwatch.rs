@@ -0,0 +1,437 @@
+use crate::handler::{
+    aws_error, get_required, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
+use crate::models::Log;
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, CreateLogStreamError, CreateLogStreamRequest,
+    InputLogEvent, PutLogEventsError, PutLogEventsRequest, RejectedLogEventsInfo,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use tokio::sync::Mutex;
+
+const MAX_BATCH_BYTES: usize = 1048576;
+const MAX_BATCH_EVENTS: usize = 10000;
+const MAX_BATCH_SPAN: i64 = 24 * 60 * 60 * 1000;
+const EVENT_OVERHEAD: usize = 26;
+const MAX_EVENT_BYTES: usize = 262144 - EVENT_OVERHEAD;
+
+struct Stream {
+    created: bool,
+    sequence_token: Option<String>,
+}
+
+pub struct CloudWatch {
+    log_group_name: String,
+    log_stream_name: String,
+    stream: Mutex<Stream>,
+    client: CloudWatchLogsClient,
+}
+
+struct Event {
+    timestamp: i64,
+    message: String,
+    log: Log,
+}
+
+pub fn from_env() -> Result<CloudWatch> {
+    let mut builder =
+        CloudWatch::builder().with_log_group_name(get_required("WOODCHUCK_CLOUDWATCH_LOG_GROUP")?);
+    if let Ok(log_stream_name) = std::env::var("WOODCHUCK_CLOUDWATCH_LOG_STREAM") {
+        builder = builder.with_log_stream_name(log_stream_name);
+    }
+    if let Ok(role_arn) = std::env::var("WOODCHUCK_CLOUDWATCH_ROLE_ARN") {
+        builder = builder.with_role_arn(
+            role_arn,
+            std::env::var("WOODCHUCK_CLOUDWATCH_EXTERNAL_ID").ok(),
+        );
+    }
+    if let Ok(region) = std::env::var("WOODCHUCK_CLOUDWATCH_REGION") {
+        builder = builder.with_region(region.parse()?);
+    }
+    if let Ok(endpoint) = std::env::var("WOODCHUCK_CLOUDWATCH_ENDPOINT") {
+        builder = builder.with_endpoint(endpoint);
+    }
+    builder.build()
+}
+
+/// Splits events sorted by timestamp into batches PutLogEvents accepts, by size, count and
+/// the 24 hours a single batch may span.
+fn batches(events: Vec<Event>) -> Vec<Vec<Event>> {
+    let mut batches: Vec<Vec<Event>> = Vec::new();
+    let mut bytes = 0;
+    for event in events.into_iter() {
+        let size = event.message.len() + EVENT_OVERHEAD;
+        let full = match batches.last() {
+            Some(batch) => {
+                batch.len() == MAX_BATCH_EVENTS
+                    || bytes + size > MAX_BATCH_BYTES
+                    || event.timestamp - batch[0].timestamp >= MAX_BATCH_SPAN
+            }
+            None => true,
+        };
+        if full {
+            batches.push(Vec::new());
+            bytes = 0;
+        }
+        bytes += size;
+        if let Some(batch) = batches.last_mut() {
+            batch.push(event);
+        }
+    }
+    batches
+}
+
+/// The token CloudWatch expects, taken from an `InvalidSequenceToken` message.
+fn expected_sequence_token(message: &str) -> Option<String> {
+    message
+        .rsplit("sequenceToken is: ")
+        .next()
+        .filter(|token| *token != message)
+        .map(|token| token.trim().to_string())
+        .filter(|token| token != "null")
+}
+
+/// The indexes of the events in a batch CloudWatch rejected for being too old, expired or too
+/// new, the end indexes are exclusive and the start index inclusive.
+fn rejected_events(info: &RejectedLogEventsInfo, count: usize) -> Vec<usize> {
+    let old = info
+        .too_old_log_event_end_index
+        .max(info.expired_log_event_end_index)
+        .map_or(0, |index| index.max(0) as usize);
+    let new = info
+        .too_new_log_event_start_index
+        .map_or(count, |index| index.max(0) as usize);
+    (0..count)
+        .filter(|index| *index < old || *index >= new)
+        .collect()
+}
+
+impl CloudWatch {
+    pub fn builder() -> CloudWatchBuilder {
+        CloudWatchBuilder::new()
+    }
+
+    async fn create_stream(&self) -> Result<()> {
+        let request = CreateLogStreamRequest {
+            log_group_name: self.log_group_name.clone(),
+            log_stream_name: self.log_stream_name.clone(),
+        };
+        match self.client.create_log_stream(request).await {
+            Ok(_) => {
+                log::debug!("Created log stream {}", &self.log_stream_name);
+                Ok(())
+            }
+            Err(RusotoError::Service(CreateLogStreamError::ResourceAlreadyExists(_))) => Ok(()),
+            Err(e) => Err(aws_error(e, |e| {
+                matches!(
+                    e,
+                    CreateLogStreamError::InvalidParameter(_)
+                        | CreateLogStreamError::ResourceNotFound(_)
+                )
+            })),
+        }
+    }
+
+    /// Returns the indexes of the events CloudWatch rejected, the rest were written.
+    async fn send_logs(&self, stream: &mut Stream, events: &[Event]) -> Result<Vec<usize>> {
+        let log_events: Vec<InputLogEvent> = events
+            .iter()
+            .map(|event| InputLogEvent {
+                message: event.message.clone(),
+                timestamp: event.timestamp,
+            })
+            .collect();
+
+        log::debug!("Sending {} events", &log_events.len());
+
+        // A stale sequence token is corrected once using the token from the error.
+        for _ in 0..2 {
+            let request = PutLogEventsRequest {
+                log_events: log_events.clone(),
+                log_group_name: self.log_group_name.clone(),
+                log_stream_name: self.log_stream_name.clone(),
+                sequence_token: stream.sequence_token.clone(),
+            };
+            match self.client.put_log_events(request).await {
+                Ok(response) => {
+                    stream.sequence_token = response.next_sequence_token;
+                    return Ok(match response.rejected_log_events_info {
+                        Some(rejected) => {
+                            log::error!("Rejected Log Events: {:?}", rejected);
+                            rejected_events(&rejected, events.len())
+                        }
+                        None => Vec::new(),
+                    });
+                }
+                Err(RusotoError::Service(PutLogEventsError::InvalidSequenceToken(message))) => {
+                    log::debug!("{}", &message);
+                    stream.sequence_token = expected_sequence_token(&message);
+                }
+                Err(RusotoError::Service(PutLogEventsError::DataAlreadyAccepted(message))) => {
+                    log::debug!("{}", &message);
+                    stream.sequence_token = expected_sequence_token(&message);
+                    return Ok(Vec::new());
+                }
+                Err(e) => {
+                    return Err(aws_error(e, |e| {
+                        matches!(
+                            e,
+                            PutLogEventsError::InvalidParameter(_)
+                                | PutLogEventsError::ResourceNotFound(_)
+                                | PutLogEventsError::UnrecognizedClient(_)
+                        )
+                    }))
+                }
+            }
+        }
+
+        Err(Error::msg("Invalid Sequence Token"))
+    }
+}
+
+#[async_trait]
+impl LogHandler for CloudWatch {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut stream = self.stream.lock().await;
+        if !stream.created {
+            match self.create_stream().await {
+                Ok(_) => stream.created = true,
+                Err(e) => {
+                    log::error!("{}", e);
+                    let mut failed_to_send_logs = FailedToSendLogsError::default();
+                    failed_to_send_logs.add(&logs, &e);
+                    return failed_to_send_logs.into_response();
+                }
+            }
+        }
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        let (mut events, oversized): (Vec<Event>, Vec<Event>) = logs
+            .into_iter()
+            .map(|log| Event {
+                timestamp: log.timestamp().unwrap_or_else(Utc::now).timestamp_millis(),
+                message: log.to_string(),
+                log,
+            })
+            .partition(|event| event.message.len() <= MAX_EVENT_BYTES);
+        if !oversized.is_empty() {
+            log::error!(
+                "Rejecting {} logs larger than {} bytes",
+                oversized.len(),
+                MAX_EVENT_BYTES
+            );
+            failed_to_send_logs.reason = Some(format!("Larger than {} bytes", MAX_EVENT_BYTES));
+            failed_to_send_logs
+                .rejected
+                .extend(oversized.into_iter().map(|event| event.log));
+        }
+        events.sort_by_key(|event| event.timestamp);
+
+        for (index, batch) in batches(events).into_iter().enumerate() {
+            let rslt = self.send_logs(&mut stream, &batch).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, batch.len());
+                    let logs: Vec<Log> = batch.into_iter().map(|event| event.log).collect();
+                    failed_to_send_logs.add(&logs, &e);
+                    log::error!("{}", e)
+                }
+                Ok(rejected) => {
+                    log::debug!(
+                        "Sent Chunk {} with {} items, {} rejected.",
+                        index,
+                        batch.len(),
+                        rejected.len()
+                    );
+                    if !rejected.is_empty() {
+                        failed_to_send_logs.reason =
+                            Some("Rejected as too old, too new or expired".to_string());
+                    }
+                    failed_to_send_logs
+                        .rejected
+                        .extend(rejected.into_iter().map(|index| batch[index].log.clone()));
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct CloudWatchBuilder {
+    log_group_name: Option<String>,
+    log_stream_name: Option<String>,
+    role_arn: Option<String>,
+    external_id: Option<String>,
+    region: Region,
+    endpoint: Option<String>,
+}
+
+impl CloudWatchBuilder {
+    pub fn new() -> Self {
+        CloudWatchBuilder {
+            log_group_name: None,
+            log_stream_name: None,
+            role_arn: None,
+            external_id: None,
+            region: Region::default(),
+            endpoint: None,
+        }
+    }
+
+    pub fn with_log_group_name(mut self, log_group_name: String) -> Self {
+        self.log_group_name = Some(log_group_name);
+        self
+    }
+
+    /// Defaults to the function's own log stream name.
+    pub fn with_log_stream_name(mut self, log_stream_name: String) -> Self {
+        self.log_stream_name = Some(log_stream_name);
+        self
+    }
+
+    /// Writes to the target account using credentials from assuming this role.
+    pub fn with_role_arn(mut self, role_arn: String, external_id: Option<String>) -> Self {
+        self.role_arn = Some(role_arn);
+        self.external_id = external_id;
+        self
+    }
+
+    pub fn with_region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+
+    /// Sends requests to a CloudWatch Logs compatible endpoint instead of AWS.
+    pub fn with_endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn build(self) -> Result<CloudWatch> {
+        match self {
+            Self {
+                log_group_name: Some(log_group_name),
+                ..
+            } => {
+                let function_name = std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                    .unwrap_or_else(|_| "woodchuck".to_string());
+                let log_stream_name = match self.log_stream_name {
+                    Some(log_stream_name) => log_stream_name,
+                    None => std::env::var("AWS_LAMBDA_LOG_STREAM_NAME")
+                        .unwrap_or_else(|_| function_name.clone()),
+                };
+
+                let region = match self.endpoint {
+                    Some(endpoint) => Region::Custom {
+                        name: self.region.name().to_string(),
+                        endpoint,
+                    },
+                    None => self.region,
+                };
+
+                let client = match self.role_arn {
+                    Some(role_arn) => {
+                        let provider = StsAssumeRoleSessionCredentialsProvider::new(
+                            StsClient::new(Region::default()),
+                            role_arn,
+                            format!("woodchuck-{}", function_name)
+                                .chars()
+                                .take(64)
+                                .collect(),
+                            self.external_id,
+                            None,
+                            None,
+                            None,
+                        );
+                        CloudWatchLogsClient::new_with(
+                            HttpClient::new()?,
+                            AutoRefreshingProvider::new(provider)?,
+                            region,
+                        )
+                    }
+                    None => CloudWatchLogsClient::new_with(
+                        HttpClient::new()?,
+                        ChainProvider::new(),
+                        region,
+                    ),
+                };
+
+                Ok(CloudWatch {
+                    log_group_name,
+                    log_stream_name,
+                    stream: Mutex::new(Stream {
+                        created: false,
+                        sequence_token: None,
+                    }),
+                    client,
+                })
+            }
+            Self {
+                log_group_name: None,
+                ..
+            } => Err(Error::msg("Log Group Name Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batches, expected_sequence_token, rejected_events, Event, MAX_BATCH_SPAN};
+    use crate::models::Log;
+    use rusoto_logs::RejectedLogEventsInfo;
+
+    fn event(timestamp: i64) -> Event {
+        Event {
+            timestamp,
+            message: "Hello World".to_string(),
+            log: Log::Formatted(serde_json::json!({ "data": "Hello World" })),
+        }
+    }
+
+    #[test]
+    fn splits_batches_spanning_a_day() {
+        let events = vec![
+            event(0),
+            event(1000),
+            event(MAX_BATCH_SPAN),
+            event(MAX_BATCH_SPAN + 1),
+        ];
+
+        let batches = batches(events);
+
+        assert_eq!(
+            batches.iter().map(|x| x.len()).collect::<Vec<usize>>(),
+            vec![2, 2]
+        );
+    }
+
+    #[test]
+    fn reads_expected_sequence_token() {
+        assert_eq!(
+            expected_sequence_token(
+                "The given sequenceToken is invalid. The next expected sequenceToken is: 4961"
+            ),
+            Some("4961".to_string())
+        );
+        assert_eq!(expected_sequence_token("Something else"), None);
+    }
+
+    #[test]
+    fn finds_rejected_events() {
+        let info = RejectedLogEventsInfo {
+            expired_log_event_end_index: Some(1),
+            too_new_log_event_start_index: Some(4),
+            too_old_log_event_end_index: Some(2),
+        };
+
+        assert_eq!(rejected_events(&info, 5), vec![0, 1, 4]);
+        assert!(rejected_events(&RejectedLogEventsInfo::default(), 5).is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::handler::PermanentError;
+use anyhow::Result;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// A TCP or TLS stream used by the handlers that do not speak HTTP.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type Connection = Box<dyn Stream>;
+
+pub async fn connect(host: &str, port: u16, tls: bool, timeout: Duration) -> Result<Connection> {
+    let tcp = tokio::time::timeout(timeout, TcpStream::connect((host, port))).await??;
+    tcp.set_nodelay(true)?;
+    match tls {
+        true => {
+            let mut roots = RootCertStore::empty();
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            let config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connector = TlsConnector::from(Arc::new(config));
+            let stream =
+                tokio::time::timeout(timeout, connector.connect(server_name(host)?, tcp)).await??;
+            Ok(Box::new(stream))
+        }
+        false => Ok(Box::new(tcp)),
+    }
+}
+
+fn server_name(host: &str) -> Result<ServerName> {
+    ServerName::try_from(host)
+        .map_err(|e| PermanentError(format!("Invalid TLS server name {}: {}", host, e)).into())
+}
@@ -0,0 +1,317 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::{Log, LogLevel};
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Client;
+use serde::Serialize;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Duration;
+
+const DEFAULT_SITE: &str = "datadoghq.com";
+const DEFAULT_SOURCE: &str = "lambda";
+const MAX_PAYLOAD_BYTES: usize = 5000000; //5MB uncompressed, measured on the serialized entries.
+const MAX_LOG_BYTES: usize = 1000000; //Datadog truncates single logs over 1MB.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Datadog {
+    url: String,
+    api_key: String,
+    source: String,
+    service: Option<String>,
+    tags: Option<String>,
+    client: Client,
+}
+
+#[derive(Debug, Serialize)]
+struct DatadogLog<'a> {
+    ddsource: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ddtags: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<LogLevel>,
+    message: String,
+}
+
+pub fn from_env() -> Result<Datadog> {
+    let mut builder = Datadog::builder()
+        .with_api_key(get_required("DD_API_KEY")?)
+        .with_timeout(get_timeout("DD_TIMEOUT"));
+    if let Ok(site) = std::env::var("DD_SITE") {
+        builder = builder.with_site(site);
+    }
+    if let Ok(source) = std::env::var("DD_SOURCE") {
+        builder = builder.with_source(source);
+    }
+    if let Ok(service) =
+        std::env::var("DD_SERVICE").or_else(|_| std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+    {
+        builder = builder.with_service(service);
+    }
+    if let Ok(tags) = std::env::var("DD_TAGS") {
+        builder = builder.with_tags(tags);
+    }
+    builder.build()
+}
+
+impl Datadog {
+    pub fn builder() -> DatadogBuilder {
+        DatadogBuilder::new()
+    }
+
+    fn entry(&self, log: &Log) -> Result<Vec<u8>> {
+        let mut message = log.to_string();
+        if message.len() > MAX_LOG_BYTES {
+            log::debug!("Truncating log of {} bytes", message.len());
+            let mut end = MAX_LOG_BYTES;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        Ok(serde_json::to_vec(&DatadogLog {
+            ddsource: &self.source,
+            service: self.service.as_deref(),
+            ddtags: self.tags.as_deref(),
+            status: log.level(),
+            message,
+        })?)
+    }
+
+    fn payload(&self, entries: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"[")?;
+        encoder.write_all(&entries.join(&b","[..]))?;
+        encoder.write_all(b"]")?;
+        Ok(encoder.finish()?)
+    }
+
+    async fn send_logs(&self, entries: &[Vec<u8>]) -> Result<()> {
+        let payload = self.payload(entries)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &entries.len(),
+            &payload.len()
+        );
+
+        let res = self
+            .client
+            .post(&self.url)
+            .header("DD-API-KEY", &self.api_key)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(payload)
+            .send()
+            .await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+}
+
+/// Splits the serialized entries into batches within Datadog's payload and entry limits,
+/// counting the brackets and commas of the JSON array.
+fn batches(entries: &[Vec<u8>]) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut bytes = 2;
+    for (index, entry) in entries.iter().enumerate() {
+        let length = entry.len() + 1;
+        if index > start && (bytes + length > MAX_PAYLOAD_BYTES || index - start == MAX_ENTRIES) {
+            batches.push(start..index);
+            start = index;
+            bytes = 2;
+        }
+        bytes += length;
+    }
+    if start < entries.len() {
+        batches.push(start..entries.len());
+    }
+    batches
+}
+
+#[async_trait]
+impl LogHandler for Datadog {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        let entries = match logs
+            .iter()
+            .map(|log| self.entry(log))
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("{}", e);
+                failed_to_send_logs.add(&logs, &e);
+                return failed_to_send_logs.into_response();
+            }
+        };
+
+        for (index, batch) in batches(&entries).into_iter().enumerate() {
+            let rslt = self.send_logs(&entries[batch.clone()]).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, batch.len());
+                    failed_to_send_logs.add(&logs[batch], &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, batch.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct DatadogBuilder {
+    api_key: Option<String>,
+    site: String,
+    source: String,
+    service: Option<String>,
+    tags: Option<String>,
+    timeout: Option<Duration>,
+}
+
+impl DatadogBuilder {
+    pub fn new() -> Self {
+        DatadogBuilder {
+            api_key: None,
+            site: DEFAULT_SITE.to_string(),
+            source: DEFAULT_SOURCE.to_string(),
+            service: None,
+            tags: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    pub fn with_site(mut self, site: String) -> Self {
+        self.site = site;
+        self
+    }
+
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn with_service(mut self, service: String) -> Self {
+        self.service = Some(service);
+        self
+    }
+
+    pub fn with_tags(mut self, tags: String) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Datadog> {
+        match self {
+            Self {
+                api_key: Some(api_key),
+                ..
+            } => {
+                let client = match self.timeout {
+                    Some(duration) => Client::builder().timeout(duration).build()?,
+                    None => Client::builder().build()?,
+                };
+
+                Ok(Datadog {
+                    url: format!("https://http-intake.logs.{}/api/v2/logs", self.site),
+                    api_key,
+                    source: self.source,
+                    service: self.service,
+                    tags: self.tags,
+                    client,
+                })
+            }
+            Self { api_key: None, .. } => Err(Error::msg("Api Key Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batches, Datadog, MAX_ENTRIES, MAX_LOG_BYTES, MAX_PAYLOAD_BYTES};
+    use crate::models::Log;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn datadog() -> Datadog {
+        Datadog::builder()
+            .with_api_key("key".to_string())
+            .with_service("my-function".to_string())
+            .with_tags("env:test".to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn builds_payload() {
+        let datadog = datadog();
+        let log = Log::Formatted(serde_json::json!({ "message": "Hello World", "level": "warn" }));
+
+        let entries = vec![datadog.entry(&log).unwrap()];
+        let mut payload = String::new();
+        GzDecoder::new(&datadog.payload(&entries).unwrap()[..])
+            .read_to_string(&mut payload)
+            .unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload[0]["ddsource"], "lambda");
+        assert_eq!(payload[0]["service"], "my-function");
+        assert_eq!(payload[0]["ddtags"], "env:test");
+        assert_eq!(payload[0]["status"], "WARN");
+        assert_eq!(payload[0]["message"], log.to_string());
+    }
+
+    #[test]
+    fn truncates_large_logs() {
+        let datadog = datadog();
+        let log = Log::Formatted(serde_json::Value::String("é".repeat(MAX_LOG_BYTES)));
+
+        let entry: serde_json::Value =
+            serde_json::from_slice(&datadog.entry(&log).unwrap()).unwrap();
+
+        assert!(entry["message"].as_str().unwrap().len() <= MAX_LOG_BYTES);
+    }
+
+    #[test]
+    fn batches_within_limits() {
+        let small = vec![vec![b'x'; 10]; MAX_ENTRIES + 1];
+        assert_eq!(
+            batches(&small),
+            vec![0..MAX_ENTRIES, MAX_ENTRIES..MAX_ENTRIES + 1]
+        );
+
+        let large = vec![vec![b'x'; MAX_LOG_BYTES]; 6];
+        let large_batches = batches(&large);
+        assert_eq!(large_batches, vec![0..4, 4..6]);
+        for batch in large_batches {
+            let bytes: usize = large[batch].iter().map(|x| x.len() + 1).sum::<usize>() + 1;
+            assert!(bytes <= MAX_PAYLOAD_BYTES);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::handler::{
+    get_destination, get_required, s3, sqs, BoxedLogHandler, LogHandler, LogHandlerResponse,
+    DEAD_LETTER_ENV,
+};
+use crate::models::Log;
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+const DEFAULT_PATH: &str = "/tmp/woodchuck-dead-letter.ndjson";
+const DEFAULT_KEY_TEMPLATE: &str = "dead-letter/{{function_name}}/%Y/%m/%d/{{uuid}}";
+
+/// Receives logs that could not be delivered to the destination, each wrapped with why it
+/// failed, after how many attempts and where it was going so it can be replayed later.
+pub struct DeadLetter {
+    destination: String,
+    handler: BoxedLogHandler,
+}
+
+/// The dead letter configured through `WOODCHUCK_DEAD_LETTER`, `s3`, `sqs` or `file`.
+pub fn from_env() -> Result<Option<DeadLetter>> {
+    let kind = match std::env::var(DEAD_LETTER_ENV) {
+        Ok(kind) if !kind.trim().is_empty() => kind.trim().to_lowercase(),
+        _ => return Ok(None),
+    };
+    println!("{} set to {}", DEAD_LETTER_ENV, &kind);
+    let handler: BoxedLogHandler = match kind.as_str() {
+        "s3" => {
+            let mut builder = s3::S3Archive::builder()
+                .with_bucket(get_required("WOODCHUCK_DEAD_LETTER_BUCKET")?)
+                .with_key_template(DEFAULT_KEY_TEMPLATE.to_string())
+                .with_write_immediately();
+            if let Ok(key_template) = std::env::var("WOODCHUCK_DEAD_LETTER_KEY_TEMPLATE") {
+                builder = builder.with_key_template(key_template);
+            }
+            Box::new(builder.build()?)
+        }
+        "sqs" => Box::new(sqs::Sqs::new(
+            get_required("WOODCHUCK_DEAD_LETTER_QUEUE_URL")?,
+            sqs::MessageGroup::FunctionName,
+        )),
+        "file" => Box::new(File {
+            path: PathBuf::from(
+                std::env::var("WOODCHUCK_DEAD_LETTER_PATH")
+                    .unwrap_or_else(|_| DEFAULT_PATH.to_string()),
+            ),
+        }),
+        _ => {
+            return Err(Error::msg(format!(
+                "Unknown dead letter destination {}",
+                kind
+            )))
+        }
+    };
+    Ok(Some(DeadLetter::new(get_destination(), handler)))
+}
+
+impl DeadLetter {
+    pub fn new(destination: String, handler: BoxedLogHandler) -> Self {
+        DeadLetter {
+            destination,
+            handler,
+        }
+    }
+
+    fn letter(&self, log: &Log, reason: Option<&str>, attempts: u32, failed_at: &str) -> Log {
+        let log = match log {
+            Log::Unformatted(data) => serde_json::to_value(data).unwrap_or(Value::Null),
+            Log::Formatted(data) => data.clone(),
+        };
+        Log::Formatted(json!({
+            "destination": self.destination,
+            "reason": reason.unwrap_or("unknown"),
+            "attempts": attempts,
+            "failed_at": failed_at,
+            "log": log,
+        }))
+    }
+
+    /// Returns the logs that could not be dead lettered.
+    pub async fn send(&self, logs: &[Log], reason: Option<&str>, attempts: u32) -> Vec<Log> {
+        if logs.is_empty() {
+            return Vec::new();
+        }
+        let failed_at = Utc::now().to_rfc3339();
+        let letters: Vec<Log> = logs
+            .iter()
+            .map(|log| self.letter(log, reason, attempts, &failed_at))
+            .collect();
+
+        match self.handler.handle_logs(letters.clone()).await {
+            Ok(_) => {
+                println!("dead lettered {} logs", logs.len());
+                Vec::new()
+            }
+            Err(failed) => {
+                println!("failed to dead letter {} logs", failed.len());
+                let mut failed = failed.logs.iter().chain(failed.rejected.iter()).fold(
+                    HashMap::new(),
+                    |mut counts, letter| {
+                        *counts.entry(letter.to_string()).or_insert(0) += 1;
+                        counts
+                    },
+                );
+                logs.iter()
+                    .zip(letters.iter())
+                    .filter(|(_, letter)| match failed.get_mut(&letter.to_string()) {
+                        Some(count) if *count > 0 => {
+                            *count -= 1;
+                            true
+                        }
+                        _ => false,
+                    })
+                    .map(|(log, _)| log.clone())
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Appends NDJSON to a local file.
+struct File {
+    path: PathBuf,
+}
+
+#[async_trait]
+impl LogHandler for File {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let payload: String = logs.iter().map(|x| x.to_string() + "\n").collect();
+        let rslt = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(payload.as_bytes()));
+        match rslt {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("{}", e);
+                Err(logs.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeadLetter, File};
+    use crate::models::Log;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn writes_failure_details() {
+        let path: PathBuf = std::env::temp_dir().join(format!("{}.ndjson", uuid::Uuid::new_v4()));
+        let dead_letter =
+            DeadLetter::new("loggly".to_string(), Box::new(File { path: path.clone() }));
+        let log = Log::Formatted(serde_json::json!({ "message": "Hello World" }));
+
+        let failed = dead_letter
+            .send(&[log], Some("Error Sending Logs: Status:400"), 3)
+            .await;
+        assert!(failed.is_empty());
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let letter: serde_json::Value = serde_json::from_str(written.trim()).unwrap();
+        assert_eq!(letter["destination"], "loggly");
+        assert_eq!(letter["reason"], "Error Sending Logs: Status:400");
+        assert_eq!(letter["attempts"], 3);
+        assert_eq!(letter["log"]["message"], "Hello World");
+    }
+
+    #[tokio::test]
+    async fn returns_logs_it_could_not_write() {
+        let path = std::env::temp_dir();
+        let dead_letter = DeadLetter::new("loggly".to_string(), Box::new(File { path }));
+        let logs = vec![
+            Log::Formatted(serde_json::json!({ "message": "Hello" })),
+            Log::Formatted(serde_json::json!({ "message": "World" })),
+        ];
+
+        let failed = dead_letter.send(&logs, None, 1).await;
+
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].to_string(), logs[0].to_string());
+        assert_eq!(failed[1].to_string(), logs[1].to_string());
+    }
+}
@@ -0,0 +1,360 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Utc;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const DEFAULT_INDEX: &str = "woodchuck-%Y.%m.%d";
+
+#[derive(Debug, Clone)]
+enum Auth {
+    Basic { username: String, password: String },
+    ApiKey(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Elasticsearch {
+    url: String,
+    index: String,
+    auth: Option<Auth>,
+    client: Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<std::collections::HashMap<String, BulkItem>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkItem {
+    status: u16,
+    error: Option<Value>,
+}
+
+pub fn from_env() -> Result<Elasticsearch> {
+    let mut builder = Elasticsearch::builder()
+        .with_url(get_required("ELASTICSEARCH_URL")?)
+        .with_timeout(get_timeout("ELASTICSEARCH_TIMEOUT"));
+    if let Ok(index) = std::env::var("ELASTICSEARCH_INDEX") {
+        builder = builder.with_index(index);
+    }
+    if let (Ok(username), Ok(password)) = (
+        std::env::var("ELASTICSEARCH_USERNAME"),
+        std::env::var("ELASTICSEARCH_PASSWORD"),
+    ) {
+        builder = builder.with_basic_auth(username, password);
+    }
+    if let Ok(api_key) = std::env::var("ELASTICSEARCH_API_KEY") {
+        builder = builder.with_api_key(api_key);
+    }
+    builder.build()
+}
+
+impl Elasticsearch {
+    pub fn builder() -> ElasticsearchBuilder {
+        ElasticsearchBuilder::new()
+    }
+
+    fn index_for(&self, log: &Log) -> String {
+        log.timestamp()
+            .unwrap_or_else(Utc::now)
+            .format(&self.index)
+            .to_string()
+    }
+
+    fn payload(&self, logs: &[Log]) -> Result<String> {
+        let mut payload = String::new();
+        for log in logs.iter() {
+            let action = json!({ "index": { "_index": self.index_for(log) } });
+            let document = match log {
+                Log::Unformatted(data) => serde_json::to_value(data)?,
+                Log::Formatted(data @ Value::Object(_)) => data.clone(),
+                Log::Formatted(data) => json!({ "message": data }),
+            };
+            payload.push_str(&action.to_string());
+            payload.push('\n');
+            payload.push_str(&document.to_string());
+            payload.push('\n');
+        }
+        Ok(payload)
+    }
+
+    /// Returns the logs that were rejected by the bulk request, the rest were indexed. Items
+    /// rejected with a client error, such as a mapping conflict, are not worth retrying.
+    async fn send_logs(&self, logs: &[Log]) -> Result<FailedToSendLogsError> {
+        let payload = self.payload(logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(payload);
+        let request = match &self.auth {
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(Auth::ApiKey(api_key)) => {
+                request.header(AUTHORIZATION, format!("ApiKey {}", api_key))
+            }
+            None => request,
+        };
+        let res = request.send().await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        // The bulk request was accepted, so the logs are not sent again when the response
+        // cannot be read as that would index them twice.
+        match res.bytes().await {
+            Ok(body) => bulk_failures(logs, &body),
+            Err(e) => {
+                log::error!("Unable to read bulk response, assuming it succeeded: {}", e);
+                Ok(FailedToSendLogsError::default())
+            }
+        }
+    }
+}
+
+/// The failed items of an accepted bulk request, an unparsable response is taken as success.
+fn bulk_failures(logs: &[Log], body: &[u8]) -> Result<FailedToSendLogsError> {
+    match serde_json::from_slice(body) {
+        Ok(response) => failed_items(logs, response),
+        Err(e) => {
+            log::error!(
+                "Unable to parse bulk response, assuming it succeeded: {}",
+                e
+            );
+            Ok(FailedToSendLogsError::default())
+        }
+    }
+}
+
+/// Splits the logs whose bulk items failed into those worth retrying and those Elasticsearch
+/// rejected, relying on the items being in the same order as the logs.
+fn failed_items(logs: &[Log], response: BulkResponse) -> Result<FailedToSendLogsError> {
+    if !response.errors {
+        return Ok(FailedToSendLogsError::default());
+    }
+
+    ensure!(
+        response.items.len() == logs.len(),
+        "Bulk response has {} items for {} logs",
+        response.items.len(),
+        logs.len()
+    );
+
+    let mut rejected = FailedToSendLogsError::default();
+    for (log, item) in logs.iter().zip(response.items.iter()) {
+        if let Some(result) = item
+            .values()
+            .find(|result| !(200..=299).contains(&result.status))
+        {
+            log::error!("Rejected by bulk request: {:?}", result.error);
+            match StatusCode::from_u16(result.status) {
+                Ok(status) if !StatusError(status).is_retryable() => {
+                    rejected.rejected.push(log.clone())
+                }
+                _ => rejected.logs.push(log.clone()),
+            }
+        }
+    }
+    Ok(rejected)
+}
+
+#[async_trait]
+impl LogHandler for Elasticsearch {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(4900000);
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                Ok(rejected) => {
+                    log::debug!(
+                        "Sent Chunk {} with {} items, {} rejected.",
+                        index,
+                        chunk.len(),
+                        rejected.len()
+                    );
+                    failed_to_send_logs.extend(rejected);
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct ElasticsearchBuilder {
+    url: Option<String>,
+    index: String,
+    auth: Option<Auth>,
+    timeout: Option<Duration>,
+}
+
+impl ElasticsearchBuilder {
+    pub fn new() -> Self {
+        ElasticsearchBuilder {
+            url: None,
+            index: DEFAULT_INDEX.to_string(),
+            auth: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    /// The index name, `strftime` specifiers are replaced using the log's timestamp.
+    pub fn with_index(mut self, index: String) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn with_basic_auth(mut self, username: String, password: String) -> Self {
+        self.auth = Some(Auth::Basic { username, password });
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.auth = Some(Auth::ApiKey(api_key));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Elasticsearch> {
+        match self {
+            Self { url: Some(url), .. } => {
+                ensure!(
+                    !StrftimeItems::new(&self.index).any(|item| item == Item::Error),
+                    "Invalid Index Pattern {}",
+                    self.index
+                );
+
+                let client = match self.timeout {
+                    Some(duration) => Client::builder().timeout(duration).build()?,
+                    None => Client::builder().build()?,
+                };
+
+                Ok(Elasticsearch {
+                    url: format!("{}/_bulk", url.trim_end_matches('/')),
+                    index: self.index,
+                    auth: self.auth,
+                    client,
+                })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bulk_failures, failed_items, BulkResponse, Elasticsearch};
+    use crate::models::Log;
+
+    fn logs(count: usize) -> Vec<Log> {
+        (0..count)
+            .map(|index| Log::Formatted(serde_json::json!({ "message": index })))
+            .collect()
+    }
+
+    #[test]
+    fn index_uses_log_timestamp() {
+        let elasticsearch = Elasticsearch::builder()
+            .with_url("http://localhost:9200".to_string())
+            .with_index("logs-%Y.%m.%d".to_string())
+            .build()
+            .unwrap();
+        let log = Log::Formatted(serde_json::json!({ "timestamp": "2020-11-18T23:52:30.128Z" }));
+
+        assert_eq!(elasticsearch.index_for(&log), "logs-2020.11.18");
+    }
+
+    #[test]
+    fn splits_rejected_from_retryable_items() {
+        let response: BulkResponse = serde_json::from_str(
+            r#"{
+                "took": 30,
+                "errors": true,
+                "items": [
+                    { "index": { "_index": "logs", "status": 201 } },
+                    { "index": { "_index": "logs", "status": 400, "error": { "type": "mapper_parsing_exception", "reason": "failed to parse field [level]" } } },
+                    { "index": { "_index": "logs", "status": 429, "error": { "type": "es_rejected_execution_exception", "reason": "rejected execution" } } },
+                    { "index": { "_index": "logs", "status": 503, "error": { "type": "unavailable_shards_exception", "reason": "primary shard is not active" } } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let logs = logs(4);
+
+        let failed = failed_items(&logs, response).unwrap();
+
+        let rejected: Vec<String> = failed.rejected.iter().map(|x| x.to_string()).collect();
+        let retryable: Vec<String> = failed.logs.iter().map(|x| x.to_string()).collect();
+        assert_eq!(rejected, vec![logs[1].to_string()]);
+        assert_eq!(retryable, vec![logs[2].to_string(), logs[3].to_string()]);
+    }
+
+    #[test]
+    fn rejects_mismatched_item_count() {
+        let response: BulkResponse = serde_json::from_str(
+            r#"{ "errors": true, "items": [ { "index": { "status": 400 } } ] }"#,
+        )
+        .unwrap();
+
+        assert!(failed_items(&logs(2), response).is_err());
+    }
+
+    #[test]
+    fn ignores_items_without_errors() {
+        let response: BulkResponse =
+            serde_json::from_str(r#"{ "errors": false, "items": [] }"#).unwrap();
+
+        assert!(failed_items(&logs(2), response).unwrap().is_empty());
+    }
+
+    #[test]
+    fn does_not_retry_unparsable_responses() {
+        let failed = bulk_failures(&logs(2), b"<html>Bad Gateway</html>").unwrap();
+        assert!(failed.is_empty());
+
+        let failed = bulk_failures(
+            &logs(1),
+            br#"{"took":3,"errors":true,"items":[{"index":{"status":503}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(failed.logs.len(), 1);
+    }
+}
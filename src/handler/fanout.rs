@@ -0,0 +1,382 @@
+use crate::extension::retry::RetryPolicy;
+use crate::handler::{BoxedLogHandler, FailedToSendLogsError, LogHandler, LogHandlerResponse};
+use crate::models::Log;
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// Logs a destination failed to take, retried against that destination alone.
+#[derive(Default)]
+struct Pending {
+    logs: Vec<Log>,
+    attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+struct Destination {
+    name: String,
+    handler: BoxedLogHandler,
+    pending: Mutex<Pending>,
+}
+
+/// Sends every batch to all of its destinations concurrently.
+///
+/// Failures are kept per destination: logs a destination fails to take are held and retried
+/// against that destination only, backing off with the retry policy, so destinations that
+/// accepted them never receive them twice. Once a destination has failed `max_attempts` times
+/// in a row its held logs are given up on and returned as rejected, as are logs a destination
+/// rejected outright.
+pub struct FanOut {
+    destinations: Vec<Destination>,
+    policy: RetryPolicy,
+}
+
+impl FanOut {
+    pub fn new(destinations: Vec<(String, BoxedLogHandler)>, policy: RetryPolicy) -> Self {
+        FanOut {
+            destinations: destinations
+                .into_iter()
+                .map(|(name, handler)| Destination {
+                    name,
+                    handler,
+                    pending: Mutex::new(Pending::default()),
+                })
+                .collect(),
+            policy,
+        }
+    }
+
+    /// Sends the destination's held logs followed by `logs`, holding on to whatever fails.
+    async fn send(&self, destination: &Destination, logs: Vec<Log>) -> FailedToSendLogsError {
+        let mut pending = destination.pending.lock().await;
+        let mut batch = std::mem::take(&mut pending.logs);
+        batch.extend(logs);
+        if batch.is_empty() {
+            return FailedToSendLogsError::default();
+        }
+
+        log::debug!("Sending {} logs to {}", batch.len(), destination.name);
+        let failed = match destination.handler.handle_logs(batch).await {
+            Ok(_) => {
+                pending.attempts = 0;
+                pending.retry_at = None;
+                return FailedToSendLogsError::default();
+            }
+            Err(failed) => failed,
+        };
+
+        log::error!(
+            "{} failed to send {} logs, {} rejected",
+            destination.name,
+            failed.logs.len(),
+            failed.rejected.len()
+        );
+        let reason = failed
+            .reason
+            .map(|reason| format!("{}: {}", destination.name, reason));
+        pending.logs.extend(failed.logs);
+        let mut given_up = FailedToSendLogsError {
+            logs: Vec::new(),
+            rejected: failed.rejected,
+            reason,
+        };
+        if !pending.logs.is_empty() {
+            pending.attempts += 1;
+            if pending.attempts >= self.policy.max_attempts {
+                println!(
+                    "giving up on {} logs for {} after {} attempts",
+                    pending.logs.len(),
+                    destination.name,
+                    pending.attempts
+                );
+                given_up.rejected.append(&mut pending.logs);
+                pending.attempts = 0;
+                pending.retry_at = None;
+            } else {
+                pending.retry_at = Some(Instant::now() + self.policy.delay(pending.attempts - 1));
+            }
+        }
+        given_up
+    }
+}
+
+/// Combines the logs each destination rejected, a log rejected by several destinations is only
+/// returned once while identical logs rejected by one destination are each kept.
+fn union(lists: Vec<Vec<Log>>) -> Vec<Log> {
+    let mut most: HashMap<String, usize> = HashMap::new();
+    let mut logs = Vec::new();
+    for list in lists.into_iter() {
+        let mut copies: HashMap<String, usize> = HashMap::new();
+        for log in list.into_iter() {
+            let key = log.to_string();
+            let copy = copies.entry(key.clone()).or_insert(0);
+            *copy += 1;
+            let kept = most.entry(key).or_insert(0);
+            if *copy > *kept {
+                *kept = *copy;
+                logs.push(log);
+            }
+        }
+    }
+    logs
+}
+
+/// Merges the failures of every destination, deduplicating what they rejected.
+fn merge(results: Vec<FailedToSendLogsError>) -> FailedToSendLogsError {
+    let mut merged = FailedToSendLogsError::default();
+    let mut rejected = Vec::new();
+    for failed in results.into_iter() {
+        merged.logs.extend(failed.logs);
+        rejected.push(failed.rejected);
+        if failed.reason.is_some() {
+            merged.reason = failed.reason;
+        }
+    }
+    merged.rejected = union(rejected);
+    merged
+}
+
+#[async_trait]
+impl LogHandler for FanOut {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        merge(
+            join_all(
+                self.destinations
+                    .iter()
+                    .map(|destination| self.send(destination, logs.clone())),
+            )
+            .await,
+        )
+        .into_response()
+    }
+
+    /// Sends the held logs of every destination and flushes them, failing while any destination
+    /// is still holding logs so shutdown keeps trying until its deadline.
+    async fn flush(&self) -> LogHandlerResponse {
+        let mut results = join_all(
+            self.destinations
+                .iter()
+                .map(|destination| self.send(destination, Vec::new())),
+        )
+        .await;
+        for (destination, result) in self.destinations.iter().zip(
+            join_all(
+                self.destinations
+                    .iter()
+                    .map(|destination| destination.handler.flush()),
+            )
+            .await,
+        ) {
+            if let Err(failed) = result {
+                log::error!("{} failed to flush {} logs", destination.name, failed.len());
+                results.push(failed);
+            }
+        }
+
+        let mut failed = merge(results);
+        for destination in self.destinations.iter() {
+            let held = destination.pending.lock().await.logs.len();
+            if held > 0 {
+                failed.reason = Some(format!("{} logs held for {}", held, destination.name));
+            }
+        }
+        match failed.is_empty() && failed.reason.is_none() {
+            true => Ok(()),
+            false => Err(failed),
+        }
+    }
+
+    /// Retries the held logs of destinations whose backoff has passed.
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        let mut results = Vec::new();
+        for destination in self.destinations.iter() {
+            let due = destination
+                .pending
+                .lock()
+                .await
+                .retry_at
+                .is_some_and(|retry_at| retry_at <= Instant::now());
+            if due {
+                results.push(self.send(destination, Vec::new()).await);
+            }
+            if let Err(failed) = destination.handler.flush_expired().await {
+                results.push(failed);
+            }
+        }
+        merge(results).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FanOut;
+    use crate::extension::retry::RetryPolicy;
+    use crate::handler::{BoxedLogHandler, FailedToSendLogsError, LogHandler, LogHandlerResponse};
+    use crate::models::Log;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        Accept,
+        Fail,
+        Reject,
+    }
+
+    struct Recording {
+        outcome: Arc<Mutex<Outcome>>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogHandler for Recording {
+        async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+            self.received
+                .lock()
+                .await
+                .extend(logs.iter().map(|x| x.to_string()));
+            match *self.outcome.lock().await {
+                Outcome::Accept => Ok(()),
+                Outcome::Fail => Err(logs.into()),
+                Outcome::Reject => Err(FailedToSendLogsError {
+                    rejected: logs,
+                    ..Default::default()
+                }),
+            }
+        }
+    }
+
+    struct Destination {
+        outcome: Arc<Mutex<Outcome>>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    fn log(message: &str) -> Log {
+        Log::Formatted(serde_json::json!({ "data": message }))
+    }
+
+    fn fanout(outcomes: [Outcome; 2], max_attempts: u32) -> (FanOut, [Destination; 2]) {
+        let destinations = outcomes.map(|outcome| Destination {
+            outcome: Arc::new(Mutex::new(outcome)),
+            received: Arc::new(Mutex::new(Vec::new())),
+        });
+        let handlers = destinations
+            .iter()
+            .enumerate()
+            .map(|(index, destination)| {
+                (
+                    format!("destination-{}", index),
+                    Box::new(Recording {
+                        outcome: destination.outcome.clone(),
+                        received: destination.received.clone(),
+                    }) as BoxedLogHandler,
+                )
+            })
+            .collect();
+        let policy = RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: false,
+            budget: None,
+        };
+        (FanOut::new(handlers, policy), destinations)
+    }
+
+    #[tokio::test]
+    async fn retries_only_the_failing_destination() {
+        //Arrange
+        let (fanout, [accepting, failing]) = fanout([Outcome::Accept, Outcome::Fail], 5);
+
+        //Act
+        let first = fanout.handle_logs(vec![log("Hello")]).await;
+        let second = fanout.handle_logs(vec![log("World")]).await;
+        *failing.outcome.lock().await = Outcome::Accept;
+        let retried = fanout.flush_expired().await;
+
+        //Assert
+        assert!(first.is_ok() && second.is_ok() && retried.is_ok());
+        assert_eq!(
+            *accepting.received.lock().await,
+            vec![log("Hello").to_string(), log("World").to_string()]
+        );
+        assert_eq!(
+            *failing.received.lock().await,
+            vec![
+                log("Hello").to_string(),
+                log("Hello").to_string(),
+                log("World").to_string(),
+                log("Hello").to_string(),
+                log("World").to_string()
+            ]
+        );
+        assert!(fanout.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        //Arrange
+        let (fanout, [accepting, _]) = fanout([Outcome::Accept, Outcome::Fail], 2);
+
+        //Act
+        let first = fanout.handle_logs(vec![log("Hello")]).await;
+        let rejected = match fanout.flush().await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e.rejected,
+        };
+
+        //Assert
+        assert!(first.is_ok());
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(accepting.received.lock().await.len(), 1);
+        assert!(fanout.flush().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn holds_logs_until_flushed() {
+        //Arrange
+        let (fanout, _) = fanout([Outcome::Accept, Outcome::Fail], 5);
+
+        //Act
+        let _ = fanout.handle_logs(vec![log("Hello")]).await;
+        let flushed = fanout.flush().await;
+
+        //Assert
+        match flushed {
+            Ok(_) => panic!("Expected held logs"),
+            Err(e) => {
+                assert!(e.is_empty());
+                assert_eq!(e.reason.as_deref(), Some("1 logs held for destination-1"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_each_rejected_log_once() {
+        //Arrange
+        let (fanout, _) = fanout([Outcome::Reject, Outcome::Reject], 5);
+        let logs = vec![log("Hello World"), log("Hello World"), log("Goodbye")];
+
+        //Act
+        let failed = match fanout.handle_logs(logs).await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e,
+        };
+
+        //Assert
+        assert!(failed.logs.is_empty());
+        let messages: Vec<String> = failed.rejected.iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                log("Hello World").to_string(),
+                log("Hello World").to_string(),
+                log("Goodbye").to_string()
+            ]
+        );
+    }
+}
@@ -1,16 +1,34 @@
-use crate::handler::{LogHandler, LogHandlerResponse};
+use crate::handler::{
+    aws_error, get_required, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
 use crate::models::Log;
-use anyhow::Result;
+use anyhow::{ensure, Error, Result};
 use async_trait::async_trait;
 use byte_chunk::SafeByteChunkedMut;
 use rusoto_core::Region;
-use rusoto_firehose::{KinesisFirehose, KinesisFirehoseClient, PutRecordInput, Record};
+use rusoto_firehose::{
+    KinesisFirehose, KinesisFirehoseClient, PutRecordBatchError, PutRecordBatchInput,
+    PutRecordError, PutRecordInput, Record,
+};
 
 use serde::Serialize;
 
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 3900000; //4MB per PutRecordBatch, give ourselves 100kb overhead.
+const MAX_RECORD_BYTES: usize = 1000000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    /// Every chunk is wrapped with the metadata into a single record.
+    Record,
+    /// Each group of logs is sent as its own newline delimited record through `PutRecordBatch`.
+    Batch { group_size: usize },
+}
+
 pub struct Firehose {
     delivery_stream_name: String,
     metadata: serde_json::Value,
+    mode: Mode,
     client: KinesisFirehoseClient,
 }
 
@@ -20,15 +38,70 @@ pub struct FirehoseData {
     logs: Vec<String>,
 }
 
+pub fn from_env() -> Result<Firehose> {
+    let stream = get_required("WOODCHUCK_FIREHOSE_TARGET")?;
+    match std::env::var("WOODCHUCK_FIREHOSE_MODE").as_deref() {
+        Ok("batch") => {
+            let group_size = match std::env::var("WOODCHUCK_FIREHOSE_GROUP_SIZE") {
+                Ok(group_size) => group_size.parse()?,
+                Err(_) => 1,
+            };
+            Firehose::batch(stream, group_size)
+        }
+        Ok("record") | Err(_) => {
+            let metadata =
+                serde_json::from_str(get_required("WOODCHUCK_FIREHOSE_METADATA")?.as_ref())?;
+            Ok(Firehose::new(stream, metadata))
+        }
+        Ok(mode) => Err(Error::msg(format!("Unable to parse {} as Mode", mode))),
+    }
+}
+
+/// Splits logs into the contiguous groups sent as individual records, starting a new group
+/// once it holds `group_size` logs or would exceed the record size limit.
+fn group(logs: &[Log], group_size: usize) -> Vec<&[Log]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+    for (index, log) in logs.iter().enumerate() {
+        let size = log.to_string().len() + 1;
+        if index > start && (index - start == group_size || bytes + size > MAX_RECORD_BYTES) {
+            groups.push(&logs[start..index]);
+            start = index;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+    if start < logs.len() {
+        groups.push(&logs[start..]);
+    }
+    groups
+}
+
+fn record_data(logs: &[Log]) -> String {
+    logs.iter().map(|x| x.to_string() + "\n").collect()
+}
+
 impl Firehose {
     pub fn new(delivery_stream_name: String, metadata: serde_json::Value) -> Self {
         Firehose {
             delivery_stream_name,
             metadata,
+            mode: Mode::Record,
             client: KinesisFirehoseClient::new(Region::default()),
         }
     }
 
+    pub fn batch(delivery_stream_name: String, group_size: usize) -> Result<Self> {
+        ensure!(group_size > 0, "Group Size must be positive");
+        Ok(Firehose {
+            delivery_stream_name,
+            metadata: serde_json::Value::Null,
+            mode: Mode::Batch { group_size },
+            client: KinesisFirehoseClient::new(Region::default()),
+        })
+    }
+
     async fn send_logs(&self, logs: &[Log]) -> Result<()> {
         let collection: Vec<String> = logs.iter().map(|x| x.to_string()).collect::<Vec<String>>();
 
@@ -41,42 +114,179 @@ impl Firehose {
 
         let encoded_data = base64::encode(data);
 
-        let record = Record { data: encoded_data.into() };
+        let record = Record {
+            data: encoded_data.into(),
+        };
 
         let input = PutRecordInput {
             delivery_stream_name: self.delivery_stream_name.clone(),
             record,
         };
 
-        let _ = self.client.put_record(input).await?;
+        let _ =
+            self.client.put_record(input).await.map_err(|e| {
+                aws_error(e, |e| !matches!(e, PutRecordError::ServiceUnavailable(_)))
+            })?;
 
         Ok(())
     }
+
+    /// Returns the logs whose records were rejected, the rest were delivered.
+    async fn send_batch(&self, groups: &[&[Log]]) -> Result<Vec<Log>> {
+        let records = groups
+            .iter()
+            .map(|logs| Record {
+                data: record_data(logs).into(),
+            })
+            .collect();
+
+        let input = PutRecordBatchInput {
+            delivery_stream_name: self.delivery_stream_name.clone(),
+            records,
+        };
+
+        let output = self.client.put_record_batch(input).await.map_err(|e| {
+            aws_error(e, |e| {
+                !matches!(e, PutRecordBatchError::ServiceUnavailable(_))
+            })
+        })?;
+
+        if output.failed_put_count == 0 {
+            return Ok(Vec::new());
+        }
+
+        ensure!(
+            output.request_responses.len() == groups.len(),
+            "PutRecordBatch response has {} records for {} sent",
+            output.request_responses.len(),
+            groups.len()
+        );
+        log::debug!("{} records failed", output.failed_put_count);
+
+        Ok(groups
+            .iter()
+            .zip(output.request_responses.iter())
+            .filter(|(_, response)| response.error_code.is_some())
+            .flat_map(|(logs, response)| {
+                log::error!("{:?}: {:?}", response.error_code, response.error_message);
+                logs.iter().cloned()
+            })
+            .collect())
+    }
+
+    async fn handle_batches(&self, logs: Vec<Log>, group_size: usize) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(MAX_BATCH_BYTES);
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let groups = group(chunk, group_size);
+            for batch in groups.chunks(MAX_BATCH_RECORDS) {
+                let (batch, oversize): (Vec<&[Log]>, Vec<&[Log]>) = batch
+                    .iter()
+                    .copied()
+                    .partition(|logs| record_data(logs).len() <= MAX_RECORD_BYTES);
+                if !oversize.is_empty() {
+                    log::error!(
+                        "Rejecting {} logs larger than {} bytes",
+                        oversize.len(),
+                        MAX_RECORD_BYTES
+                    );
+                    failed_to_send_logs.reason =
+                        Some(format!("Larger than {} bytes", MAX_RECORD_BYTES));
+                    failed_to_send_logs.rejected.extend(oversize.concat());
+                }
+                if batch.is_empty() {
+                    continue;
+                }
+
+                let count: usize = batch.iter().map(|logs| logs.len()).sum();
+                match self.send_batch(&batch).await {
+                    Err(e) => {
+                        log::debug!("Failed sending Chunk {} with {} items.", index, count);
+                        failed_to_send_logs.add(&batch.concat(), &e);
+                        log::error!("{}", e)
+                    }
+                    Ok(failed) => {
+                        log::debug!(
+                            "Sent Chunk {} with {} items, {} failed.",
+                            index,
+                            count,
+                            failed.len()
+                        );
+                        failed_to_send_logs.logs.extend(failed);
+                    }
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
 }
 
 #[async_trait]
 impl LogHandler for Firehose {
     async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        if let Mode::Batch { group_size } = self.mode {
+            return self.handle_batches(logs, group_size).await;
+        }
+
         let mut local_logs = logs.to_owned();
         let chunks = local_logs.byte_chunks_safe_mut(900000);
 
-        let mut failed_to_send_logs = Vec::<Log>::new();
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
 
         for (index, chunk) in chunks.enumerate() {
             let rslt = self.send_logs(chunk).await;
             match rslt {
                 Err(e) => {
                     log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
-                    failed_to_send_logs.extend_from_slice(chunk);
+                    failed_to_send_logs.add(chunk, &e);
                     log::error!("{}", e)
                 }
                 _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
             }
         }
 
-        match failed_to_send_logs.len() {
-            0 => Ok(()),
-            _ => Err(failed_to_send_logs.into()),
-        }
+        failed_to_send_logs.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{group, Firehose, MAX_RECORD_BYTES};
+    use crate::handler::LogHandler;
+    use crate::models::Log;
+
+    #[test]
+    fn groups_logs() {
+        let logs: Vec<Log> = (0..5)
+            .map(|i| Log::Formatted(serde_json::json!({ "data": i })))
+            .collect();
+
+        let groups = group(&logs, 2);
+
+        assert_eq!(
+            groups.iter().map(|x| x.len()).collect::<Vec<usize>>(),
+            vec![2, 2, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_records() {
+        let firehose = Firehose::batch("stream".to_string(), 1).unwrap();
+        let logs = vec![Log::Formatted(serde_json::Value::String(
+            "x".repeat(MAX_RECORD_BYTES),
+        ))];
+
+        let failed = match firehose.handle_logs(logs).await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e,
+        };
+
+        assert_eq!(failed.rejected.len(), 1);
+        assert!(failed.logs.is_empty());
+        assert!(failed.reason.is_some());
     }
 }
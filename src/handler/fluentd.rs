@@ -0,0 +1,519 @@
+use crate::handler::connection::{connect, Connection};
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+    PermanentError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::{DateTime, Utc};
+use rmpv::Value as MsgPack;
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+const DEFAULT_PORT: u16 = 24224;
+const EVENT_TIME: i8 = 0;
+
+fn to_msgpack(value: &Value) -> MsgPack {
+    match value {
+        Value::Null => MsgPack::Nil,
+        Value::Bool(b) => MsgPack::from(*b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => MsgPack::from(i),
+            (_, Some(u)) => MsgPack::from(u),
+            _ => MsgPack::from(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => MsgPack::from(s.as_str()),
+        Value::Array(values) => MsgPack::Array(values.iter().map(to_msgpack).collect()),
+        Value::Object(values) => MsgPack::Map(
+            values
+                .iter()
+                .map(|(key, value)| (MsgPack::from(key.as_str()), to_msgpack(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn sha512_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha512::new();
+    for part in parts.iter() {
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Looks up a key in a MessagePack map with string keys.
+fn get<'a>(map: &'a MsgPack, key: &str) -> Option<&'a MsgPack> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn bytes(value: &MsgPack) -> &[u8] {
+    match value {
+        MsgPack::Binary(b) => b,
+        MsgPack::String(s) => s.as_bytes(),
+        _ => &[],
+    }
+}
+
+/// Encodes the `EventTime` of a log, timestamps its 32 bit seconds cannot hold are replaced
+/// with the current time.
+fn event_time(timestamp: Option<DateTime<Utc>>) -> Vec<u8> {
+    let seconds = |time: &DateTime<Utc>| u32::try_from(time.timestamp()).ok();
+    let time = timestamp
+        .filter(|time| seconds(time).is_some())
+        .unwrap_or_else(Utc::now);
+    let mut event_time = Vec::with_capacity(8);
+    event_time.extend_from_slice(&seconds(&time).unwrap_or_default().to_be_bytes());
+    event_time.extend_from_slice(&time.timestamp_subsec_nanos().to_be_bytes());
+    event_time
+}
+
+/// A connection to the server along with the bytes read from it that are not yet decoded.
+struct Session {
+    stream: Connection,
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    fn new(stream: Connection) -> Self {
+        Session {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads one MessagePack object, the stream may deliver it across several reads or
+    /// together with the start of the next one.
+    async fn read_message(&mut self) -> Result<MsgPack> {
+        let mut read = [0u8; 4096];
+        loop {
+            let mut remaining = &self.buffer[..];
+            if let Ok(message) = rmpv::decode::read_value(&mut remaining) {
+                let consumed = self.buffer.len() - remaining.len();
+                self.buffer.drain(..consumed);
+                return Ok(message);
+            }
+            let n = self.stream.read(&mut read).await?;
+            ensure!(n > 0, "Connection closed by server");
+            self.buffer.extend_from_slice(&read[..n]);
+        }
+    }
+}
+
+pub struct Fluentd {
+    host: String,
+    port: u16,
+    tls: bool,
+    tag: String,
+    hostname: String,
+    shared_key: Option<String>,
+    username: String,
+    password: String,
+    ack: bool,
+    timeout: Duration,
+    connection: Mutex<Option<Session>>,
+}
+
+pub fn from_env() -> Result<Fluentd> {
+    let mut builder = Fluentd::builder()
+        .with_host(get_required("FLUENTD_HOST")?)
+        .with_timeout(get_timeout("FLUENTD_TIMEOUT"));
+    if let Ok(port) = std::env::var("FLUENTD_PORT") {
+        builder = builder.with_port(port.parse()?);
+    }
+    if let Ok(tls) = std::env::var("FLUENTD_TLS") {
+        builder = builder.with_tls(tls.parse()?);
+    }
+    if let Ok(tag) = std::env::var("FLUENTD_TAG") {
+        builder = builder.with_tag(tag);
+    }
+    if let Ok(hostname) = std::env::var("FLUENTD_HOSTNAME") {
+        builder = builder.with_hostname(hostname);
+    }
+    if let Ok(shared_key) = std::env::var("FLUENTD_SHARED_KEY") {
+        builder = builder.with_shared_key(shared_key);
+    }
+    if let Ok(username) = std::env::var("FLUENTD_USERNAME") {
+        builder = builder.with_user(username, get_required("FLUENTD_PASSWORD")?);
+    }
+    if let Ok(ack) = std::env::var("FLUENTD_ACK") {
+        builder = builder.with_ack(ack.parse()?);
+    }
+    builder.build()
+}
+
+impl Fluentd {
+    pub fn builder() -> FluentdBuilder {
+        FluentdBuilder::new()
+    }
+
+    /// Encodes the logs as a `PackedForward` message, returning it with its chunk id.
+    fn payload(&self, logs: &[Log]) -> Result<(Vec<u8>, String)> {
+        let mut entries = Vec::new();
+        for log in logs.iter() {
+            let record = match log {
+                Log::Unformatted(data) => serde_json::to_value(data)?,
+                Log::Formatted(data) => data.clone(),
+            };
+            let entry = MsgPack::Array(vec![
+                MsgPack::Ext(EVENT_TIME, event_time(log.timestamp())),
+                to_msgpack(&record),
+            ]);
+            rmpv::encode::write_value(&mut entries, &entry)?;
+        }
+
+        let chunk = base64::encode(uuid::Uuid::new_v4().as_bytes());
+        let mut option = vec![(MsgPack::from("size"), MsgPack::from(logs.len() as u64))];
+        if self.ack {
+            option.push((MsgPack::from("chunk"), MsgPack::from(chunk.as_str())));
+        }
+        let message = MsgPack::Array(vec![
+            MsgPack::from(self.tag.as_str()),
+            MsgPack::Binary(entries),
+            MsgPack::Map(option),
+        ]);
+
+        let mut payload = Vec::new();
+        rmpv::encode::write_value(&mut payload, &message)?;
+        Ok((payload, chunk))
+    }
+
+    /// Answers the server's `HELO` with a `PING` and checks its `PONG`.
+    async fn handshake(&self, session: &mut Session, shared_key: &str) -> Result<()> {
+        let helo = session.read_message().await?;
+        let helo = helo
+            .as_array()
+            .filter(|helo| helo.first().and_then(|x| x.as_str()) == Some("HELO"))
+            .and_then(|helo| helo.get(1))
+            .ok_or_else(|| Error::msg("Expected HELO"))?;
+        let nonce = get(helo, "nonce").map(bytes).unwrap_or_default().to_vec();
+        let auth = get(helo, "auth").map(bytes).unwrap_or_default().to_vec();
+
+        let salt = uuid::Uuid::new_v4().to_simple().to_string();
+        let shared_key_digest = sha512_hex(&[
+            salt.as_bytes(),
+            self.hostname.as_bytes(),
+            nonce.as_slice(),
+            shared_key.as_bytes(),
+        ]);
+        let password_digest = match auth.len() {
+            0 => String::new(),
+            _ => sha512_hex(&[
+                auth.as_slice(),
+                self.username.as_bytes(),
+                self.password.as_bytes(),
+            ]),
+        };
+        let ping = MsgPack::Array(vec![
+            MsgPack::from("PING"),
+            MsgPack::from(self.hostname.as_str()),
+            MsgPack::from(salt.as_str()),
+            MsgPack::from(shared_key_digest.as_str()),
+            MsgPack::from(self.username.as_str()),
+            MsgPack::from(password_digest.as_str()),
+        ]);
+        let mut payload = Vec::new();
+        rmpv::encode::write_value(&mut payload, &ping)?;
+        session.stream.write_all(&payload).await?;
+        session.stream.flush().await?;
+
+        let pong = session.read_message().await?;
+        let pong = pong
+            .as_array()
+            .filter(|pong| pong.first().and_then(|x| x.as_str()) == Some("PONG"))
+            .ok_or_else(|| Error::msg("Expected PONG"))?;
+        if pong.get(1).and_then(|x| x.as_bool()) != Some(true) {
+            return Err(PermanentError(format!(
+                "Authentication failed: {}",
+                pong.get(2).and_then(|x| x.as_str()).unwrap_or_default()
+            ))
+            .into());
+        }
+        let server_hostname = pong.get(3).map(bytes).unwrap_or_default();
+        let server_digest = sha512_hex(&[
+            salt.as_bytes(),
+            server_hostname,
+            nonce.as_slice(),
+            shared_key.as_bytes(),
+        ]);
+        if pong.get(4).and_then(|x| x.as_str()) != Some(server_digest.as_str()) {
+            return Err(PermanentError("Shared key mismatch".to_string()).into());
+        }
+        Ok(())
+    }
+
+    async fn open(&self) -> Result<Session> {
+        let stream = connect(&self.host, self.port, self.tls, self.timeout).await?;
+        let mut session = Session::new(stream);
+        if let Some(shared_key) = &self.shared_key {
+            tokio::time::timeout(self.timeout, self.handshake(&mut session, shared_key)).await??;
+        }
+        Ok(session)
+    }
+
+    /// Sends the logs over the reused connection, waiting for the chunk to be acknowledged.
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let (payload, chunk) = self.payload(logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.open().await?);
+        }
+        let session = connection.as_mut().unwrap();
+        let rslt = tokio::time::timeout(self.timeout, async {
+            session.stream.write_all(&payload).await?;
+            session.stream.flush().await?;
+            if self.ack {
+                let response = session.read_message().await?;
+                ensure!(
+                    get(&response, "ack").and_then(|x| x.as_str()) == Some(chunk.as_str()),
+                    "Chunk {} was not acknowledged",
+                    chunk
+                );
+            }
+            Ok::<(), Error>(())
+        })
+        .await;
+        match rslt {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                *connection = None;
+                Err(e)
+            }
+            Err(e) => {
+                *connection = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for Fluentd {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(1000000);
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct FluentdBuilder {
+    host: Option<String>,
+    port: u16,
+    tls: bool,
+    tag: Option<String>,
+    hostname: Option<String>,
+    shared_key: Option<String>,
+    username: String,
+    password: String,
+    ack: bool,
+    timeout: Option<Duration>,
+}
+
+impl FluentdBuilder {
+    pub fn new() -> Self {
+        FluentdBuilder {
+            host: None,
+            port: DEFAULT_PORT,
+            tls: false,
+            tag: None,
+            hostname: None,
+            shared_key: None,
+            username: String::new(),
+            password: String::new(),
+            ack: true,
+            timeout: None,
+        }
+    }
+
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Defaults to `lambda.<function name>`.
+    pub fn with_tag(mut self, tag: String) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// The hostname sent during the handshake, defaults to the function name.
+    pub fn with_hostname(mut self, hostname: String) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    pub fn with_shared_key(mut self, shared_key: String) -> Self {
+        self.shared_key = Some(shared_key);
+        self
+    }
+
+    /// Credentials for servers that require user authentication as well as the shared key.
+    pub fn with_user(mut self, username: String, password: String) -> Self {
+        self.username = username;
+        self.password = password;
+        self
+    }
+
+    /// Whether to wait for the server to acknowledge each chunk, enabled by default.
+    pub fn with_ack(mut self, ack: bool) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Fluentd> {
+        match self {
+            Self {
+                host: Some(host), ..
+            } => {
+                let function_name = std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                    .unwrap_or_else(|_| "woodchuck".to_string());
+                let tag = match self.tag {
+                    Some(tag) => tag,
+                    None => format!("lambda.{}", function_name),
+                };
+
+                Ok(Fluentd {
+                    host,
+                    port: self.port,
+                    tls: self.tls,
+                    tag,
+                    hostname: self.hostname.unwrap_or(function_name),
+                    shared_key: self.shared_key,
+                    username: self.username,
+                    password: self.password,
+                    ack: self.ack,
+                    timeout: self.timeout.unwrap_or(Duration::from_secs(60)),
+                    connection: Mutex::new(None),
+                })
+            }
+            Self { host: None, .. } => Err(Error::msg("Host Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{event_time, get, Fluentd, MsgPack, Session};
+    use crate::models::Log;
+    use chrono::{TimeZone, Utc};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn encodes_packed_forward() {
+        let fluentd = Fluentd::builder()
+            .with_host("localhost".to_string())
+            .with_tag("lambda.test".to_string())
+            .build()
+            .unwrap();
+        let logs = vec![
+            Log::Formatted(serde_json::json!({
+                "data": "Hello",
+                "timestamp": "2020-11-18T23:52:30.128Z"
+            })),
+            Log::Formatted(serde_json::json!({ "data": "World" })),
+        ];
+
+        let (payload, chunk) = fluentd.payload(&logs).unwrap();
+        let message = rmpv::decode::read_value(&mut &payload[..]).unwrap();
+        let message = message.as_array().unwrap();
+
+        assert_eq!(message[0].as_str(), Some("lambda.test"));
+        assert_eq!(get(&message[2], "size").and_then(|x| x.as_u64()), Some(2));
+        assert_eq!(
+            get(&message[2], "chunk").and_then(|x| x.as_str()),
+            Some(chunk.as_str())
+        );
+
+        let entries = match &message[1] {
+            MsgPack::Binary(entries) => entries.clone(),
+            _ => panic!("Expected binary entries"),
+        };
+        let first = rmpv::decode::read_value(&mut &entries[..]).unwrap();
+        let first = first.as_array().unwrap();
+        assert_eq!(
+            first[0],
+            MsgPack::Ext(0, vec![0x5f, 0xb5, 0xb3, 0xbe, 0x07, 0xa1, 0x20, 0x00])
+        );
+        assert_eq!(
+            get(&first[1], "data").and_then(|x| x.as_str()),
+            Some("Hello")
+        );
+    }
+
+    #[test]
+    fn replaces_out_of_range_event_times() {
+        let time = Utc.timestamp(1605743550, 128000000);
+        assert_eq!(
+            event_time(Some(time)),
+            vec![0x5f, 0xb5, 0xb3, 0xbe, 0x07, 0xa1, 0x20, 0x00]
+        );
+
+        let before = Utc::now().timestamp() as u64;
+        for time in [
+            Utc.ymd(1969, 7, 20).and_hms(20, 17, 40),
+            Utc.ymd(2262, 4, 11).and_hms(0, 0, 0),
+        ] {
+            let encoded = event_time(Some(time));
+            let seconds = u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]);
+            assert!(seconds as u64 >= before);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_bytes_read_past_a_message() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut messages = Vec::new();
+        rmpv::encode::write_value(&mut messages, &MsgPack::from("HELO")).unwrap();
+        rmpv::encode::write_value(&mut messages, &MsgPack::from("PONG")).unwrap();
+        server.write_all(&messages).await.unwrap();
+
+        let mut session = Session::new(Box::new(client));
+        assert_eq!(session.read_message().await.unwrap().as_str(), Some("HELO"));
+        assert_eq!(session.read_message().await.unwrap().as_str(), Some("PONG"));
+    }
+}
@@ -0,0 +1,576 @@
+use crate::handler::connection::{connect, Connection};
+use crate::handler::{
+    get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::{Log, LogLevel};
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use futures::future::join_all;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Client;
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+const DEFAULT_PORT: u16 = 12201;
+const DEFAULT_CHUNK_SIZE: usize = 1420;
+const CHUNK_HEADER: usize = 12;
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const MAX_CHUNKS: usize = 128;
+const HTTP_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+    Http,
+}
+
+impl TryFrom<&str> for Protocol {
+    type Error = anyhow::Error;
+    fn try_from(protocol: &str) -> Result<Self> {
+        match protocol {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            "tls" => Ok(Protocol::Tls),
+            "http" => Ok(Protocol::Http),
+            _ => Err(Error::msg(format!(
+                "Unable to parse {} as Protocol",
+                protocol
+            ))),
+        }
+    }
+}
+
+fn level(level: Option<LogLevel>) -> u8 {
+    match level {
+        Some(LogLevel::Critical) => 2,
+        Some(LogLevel::Error) => 3,
+        Some(LogLevel::Warn) => 4,
+        Some(LogLevel::Info) | None => 6,
+        Some(LogLevel::Debug) | Some(LogLevel::Trace) => 7,
+    }
+}
+
+/// GELF field names may only contain letters, numbers, underscores, dashes and dots.
+fn field_name(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// Flattens nested objects into `_parent_child` fields, arrays are kept as JSON strings.
+fn flatten(prefix: &str, value: &Value, fields: &mut Map<String, Value>) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object.iter() {
+                flatten(&format!("{}_{}", prefix, field_name(name)), value, fields);
+            }
+        }
+        Value::Null => {}
+        Value::Array(_) => {
+            fields.insert(prefix.to_string(), Value::String(value.to_string()));
+        }
+        _ => {
+            fields.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Splits a datagram into GELF chunks sharing a random message id.
+fn chunk(message: &[u8], chunk_size: usize) -> Result<Vec<Vec<u8>>> {
+    if message.len() <= chunk_size {
+        return Ok(vec![message.to_vec()]);
+    }
+    let payloads: Vec<&[u8]> = message.chunks(chunk_size - CHUNK_HEADER).collect();
+    ensure!(
+        payloads.len() <= MAX_CHUNKS,
+        "Message of {} bytes needs more than {} chunks",
+        message.len(),
+        MAX_CHUNKS
+    );
+    let id = uuid::Uuid::new_v4();
+    Ok(payloads
+        .iter()
+        .enumerate()
+        .map(|(sequence, payload)| {
+            let mut datagram = Vec::with_capacity(CHUNK_HEADER + payload.len());
+            datagram.extend_from_slice(&CHUNK_MAGIC);
+            datagram.extend_from_slice(&id.as_bytes()[..8]);
+            datagram.push(sequence as u8);
+            datagram.push(payloads.len() as u8);
+            datagram.extend_from_slice(payload);
+            datagram
+        })
+        .collect())
+}
+
+pub struct Gelf {
+    host: String,
+    port: u16,
+    url: Option<String>,
+    protocol: Protocol,
+    source: String,
+    compress: bool,
+    chunk_size: usize,
+    timeout: Duration,
+    client: Client,
+    connection: Mutex<Option<Connection>>,
+}
+
+pub fn from_env() -> Result<Gelf> {
+    let mut builder = Gelf::builder().with_timeout(get_timeout("GELF_TIMEOUT"));
+    if let Ok(protocol) = std::env::var("GELF_PROTOCOL") {
+        builder = builder.with_protocol(Protocol::try_from(protocol.to_lowercase().as_str())?);
+    }
+    if let Ok(host) = std::env::var("GELF_HOST") {
+        builder = builder.with_host(host);
+    }
+    if let Ok(port) = std::env::var("GELF_PORT") {
+        builder = builder.with_port(port.parse()?);
+    }
+    if let Ok(url) = std::env::var("GELF_URL") {
+        builder = builder.with_url(url);
+    }
+    if let Ok(source) =
+        std::env::var("GELF_SOURCE").or_else(|_| std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+    {
+        builder = builder.with_source(source);
+    }
+    if let Ok(compression) = std::env::var("GELF_COMPRESSION") {
+        builder = builder.with_compression(match compression.as_str() {
+            "gzip" => true,
+            "none" => false,
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unable to parse {} as Compression",
+                    compression
+                )))
+            }
+        });
+    }
+    if let Ok(chunk_size) = std::env::var("GELF_UDP_CHUNK_SIZE") {
+        builder = builder.with_chunk_size(chunk_size.parse()?);
+    }
+    builder.build()
+}
+
+impl Gelf {
+    pub fn builder() -> GelfBuilder {
+        GelfBuilder::new()
+    }
+
+    /// Maps a log to a GELF 1.1 message.
+    fn format(&self, log: &Log) -> Value {
+        let mut message = Map::new();
+        message.insert("version".to_string(), Value::from("1.1"));
+        message.insert("host".to_string(), Value::from(self.source.as_str()));
+        match log {
+            Log::Unformatted(data) => {
+                let text = match &data.data {
+                    Value::String(s) => s.trim_end().to_string(),
+                    other => other.to_string(),
+                };
+                let short_message = text.lines().next().unwrap_or_default().to_string();
+                if short_message != text {
+                    message.insert("full_message".to_string(), Value::from(text));
+                }
+                message.insert("short_message".to_string(), Value::from(short_message));
+                for (name, value) in [
+                    ("_request_id", &data.guid),
+                    ("_trace_id", &data.trace_id),
+                    ("_span_id", &data.span_id),
+                ]
+                .iter()
+                {
+                    if let Some(value) = value {
+                        message.insert(name.to_string(), Value::from(value.as_str()));
+                    }
+                }
+            }
+            Log::Formatted(data) => {
+                let short_message = match (&data["message"], &data["msg"]) {
+                    (Value::String(s), _) | (_, Value::String(s)) => s.clone(),
+                    _ => data.to_string(),
+                };
+                message.insert("short_message".to_string(), Value::from(short_message));
+                if let Value::Object(object) = data {
+                    for (name, value) in object.iter() {
+                        match name.as_str() {
+                            // `_id` is reserved by GELF.
+                            "id" => flatten("_log_id", value, &mut message),
+                            _ => flatten(&format!("_{}", field_name(name)), value, &mut message),
+                        }
+                    }
+                }
+            }
+        }
+        let timestamp = log.timestamp().unwrap_or_else(Utc::now);
+        message.insert(
+            "timestamp".to_string(),
+            Value::from(timestamp.timestamp_millis() as f64 / 1000.0),
+        );
+        message.insert("level".to_string(), Value::from(level(log.level())));
+        Value::Object(message)
+    }
+
+    async fn send_http(&self, url: &str, log: &Log) -> Result<()> {
+        let payload = self.format(log).to_string().into_bytes();
+        let request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json");
+        let request = match self.compress {
+            true => request
+                .header(CONTENT_ENCODING, "gzip")
+                .body(compress(&payload)?),
+            false => request.body(payload),
+        };
+
+        let res = request.send().await?;
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+
+    /// Graylog accepts one message per request, so requests are sent a few at a time.
+    async fn send_all_http(&self, url: &str, logs: &[Log]) -> FailedToSendLogsError {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+        for chunk in logs.chunks(HTTP_CONCURRENCY) {
+            let results = join_all(chunk.iter().map(|log| self.send_http(url, log))).await;
+            for (log, result) in chunk.iter().zip(results) {
+                if let Err(e) = result {
+                    log::error!("{}", e);
+                    failed_to_send_logs.add(std::slice::from_ref(log), &e);
+                }
+            }
+        }
+        failed_to_send_logs
+    }
+
+    /// Messages that cannot be split into GELF chunks are rejected, sending them again would
+    /// fail the same way.
+    async fn send_udp(&self, logs: &[Log]) -> FailedToSendLogsError {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+        let socket = match UdpSocket::bind(("0.0.0.0", 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("{}", e);
+                failed_to_send_logs.add(logs, &e.into());
+                return failed_to_send_logs;
+            }
+        };
+        if let Err(e) = socket.connect((self.host.as_str(), self.port)).await {
+            log::error!("{}", e);
+            failed_to_send_logs.add(logs, &e.into());
+            return failed_to_send_logs;
+        }
+
+        for log in logs.iter() {
+            let payload = self.format(log).to_string().into_bytes();
+            let datagrams = match self.compress {
+                true => compress(&payload).and_then(|x| chunk(&x, self.chunk_size)),
+                false => chunk(&payload, self.chunk_size),
+            };
+            let datagrams = match datagrams {
+                Ok(datagrams) => datagrams,
+                Err(e) => {
+                    log::error!("Rejecting log, {}", e);
+                    failed_to_send_logs.rejected.push(log.clone());
+                    failed_to_send_logs.reason = Some(e.to_string());
+                    continue;
+                }
+            };
+            for datagram in datagrams.iter() {
+                if let Err(e) = socket.send(datagram).await {
+                    log::error!("{}", e);
+                    failed_to_send_logs.add(std::slice::from_ref(log), &e.into());
+                    break;
+                }
+            }
+        }
+        failed_to_send_logs
+    }
+
+    /// Writes null byte delimited messages, reusing the connection between calls.
+    async fn send_stream(&self, logs: &[Log]) -> Result<()> {
+        let mut payload = Vec::new();
+        for log in logs.iter() {
+            payload.extend_from_slice(self.format(log).to_string().as_bytes());
+            payload.push(0);
+        }
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(
+                connect(
+                    &self.host,
+                    self.port,
+                    self.protocol == Protocol::Tls,
+                    self.timeout,
+                )
+                .await?,
+            );
+        }
+        let stream = connection.as_mut().unwrap();
+        let rslt = tokio::time::timeout(self.timeout, async {
+            stream.write_all(&payload).await?;
+            stream.flush().await
+        })
+        .await;
+        match rslt {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                *connection = None;
+                Err(e.into())
+            }
+            Err(e) => {
+                *connection = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for Gelf {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        match (&self.protocol, &self.url) {
+            (Protocol::Http, Some(url)) => {
+                failed_to_send_logs.extend(self.send_all_http(url, &logs).await)
+            }
+            (Protocol::Udp, _) => failed_to_send_logs.extend(self.send_udp(&logs).await),
+            _ => {
+                let mut local_logs = logs.to_owned();
+                let chunks = local_logs.byte_chunks_safe_mut(1000000);
+
+                for (index, chunk) in chunks.enumerate() {
+                    let rslt = self.send_stream(chunk).await;
+                    match rslt {
+                        Err(e) => {
+                            log::debug!(
+                                "Failed sending Chunk {} with {} items.",
+                                index,
+                                chunk.len()
+                            );
+                            failed_to_send_logs.add(chunk, &e);
+                            log::error!("{}", e)
+                        }
+                        _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+                    }
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct GelfBuilder {
+    host: Option<String>,
+    port: u16,
+    url: Option<String>,
+    protocol: Protocol,
+    source: String,
+    compress: bool,
+    chunk_size: usize,
+    timeout: Option<Duration>,
+}
+
+impl GelfBuilder {
+    pub fn new() -> Self {
+        GelfBuilder {
+            host: None,
+            port: DEFAULT_PORT,
+            url: None,
+            protocol: Protocol::Udp,
+            source: "woodchuck".to_string(),
+            compress: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            timeout: None,
+        }
+    }
+
+    /// The Graylog host for the UDP, TCP and TLS protocols.
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// The GELF input url for the HTTP protocol, e.g. `http://graylog:12201/gelf`.
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// The GELF `host` field, identifying where the logs came from.
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Gzip compresses UDP and HTTP messages, GELF over TCP does not support compression.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// The largest UDP datagram to send, larger messages are split into GELF chunks.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Gelf> {
+        ensure!(
+            self.chunk_size > CHUNK_HEADER,
+            "Chunk Size must be larger than {}",
+            CHUNK_HEADER
+        );
+        let host = match (&self.protocol, self.host) {
+            (Protocol::Http, _) if self.url.is_none() => return Err(Error::msg("Url Required")),
+            (Protocol::Http, _) => String::new(),
+            (_, Some(host)) => host,
+            (_, None) => return Err(Error::msg("Host Required")),
+        };
+
+        let client = match self.timeout {
+            Some(duration) => Client::builder().timeout(duration).build()?,
+            None => Client::builder().build()?,
+        };
+
+        Ok(Gelf {
+            host,
+            port: self.port,
+            url: self.url,
+            protocol: self.protocol,
+            source: self.source,
+            compress: self.compress,
+            chunk_size: self.chunk_size,
+            timeout: self.timeout.unwrap_or(Duration::from_secs(60)),
+            client,
+            connection: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk, Gelf, Protocol, CHUNK_MAGIC, MAX_CHUNKS};
+    use crate::handler::LogHandler;
+    use crate::models::{Log, LogLevel, StructuredLog};
+
+    #[test]
+    fn formats_gelf_message() {
+        let gelf = Gelf::builder()
+            .with_host("localhost".to_string())
+            .with_source("my-function".to_string())
+            .build()
+            .unwrap();
+        let unformatted = Log::Unformatted(StructuredLog {
+            timestamp: Some("2020-11-18T23:52:30.128Z".to_string()),
+            guid: Some("6e48723a-1596-4313-a9af-e4da9214d637".to_string()),
+            level: Some(LogLevel::Error),
+            data: serde_json::Value::String("Failed\n  at handler\n".to_string()),
+            trace_id: None,
+            span_id: None,
+        });
+        let formatted = Log::Formatted(serde_json::json!({
+            "message": "Hello World",
+            "id": 7,
+            "dd": { "trace_id": "123" }
+        }));
+
+        let unformatted = gelf.format(&unformatted);
+        let formatted = gelf.format(&formatted);
+
+        assert_eq!(unformatted["host"], "my-function");
+        assert_eq!(unformatted["short_message"], "Failed");
+        assert_eq!(unformatted["full_message"], "Failed\n  at handler");
+        assert_eq!(unformatted["level"], 3);
+        assert_eq!(unformatted["timestamp"], 1605743550.128);
+        assert_eq!(
+            unformatted["_request_id"],
+            "6e48723a-1596-4313-a9af-e4da9214d637"
+        );
+        assert_eq!(formatted["short_message"], "Hello World");
+        assert_eq!(formatted["_log_id"], 7);
+        assert_eq!(formatted["_dd_trace_id"], "123");
+    }
+
+    #[test]
+    fn chunks_large_messages() {
+        let chunks = chunk(&[b'a'; 100], 52).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0][..2], CHUNK_MAGIC);
+        assert_eq!(chunks[0][..10], chunks[2][..10]);
+        assert_eq!(chunks[2][10..12], [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_needing_too_many_chunks() {
+        let gelf = Gelf::builder()
+            .with_host("127.0.0.1".to_string())
+            .with_protocol(Protocol::Udp)
+            .with_compression(false)
+            .with_chunk_size(52)
+            .build()
+            .unwrap();
+        let logs = vec![Log::Formatted(serde_json::Value::String(
+            "a".repeat(40 * (MAX_CHUNKS + 1)),
+        ))];
+
+        let failed = match gelf.handle_logs(logs).await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e,
+        };
+
+        assert_eq!(failed.rejected.len(), 1);
+        assert!(failed.logs.is_empty());
+        assert!(failed.reason.unwrap().contains("chunks"));
+    }
+}
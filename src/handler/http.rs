@@ -0,0 +1,344 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Method};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const DEFAULT_MAX_BATCH_BYTES: usize = 4900000;
+const LOGS_PLACEHOLDER: &str = "{{logs}}";
+const COUNT_PLACEHOLDER: &str = "{{count}}";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyFormat {
+    /// One JSON log per line.
+    Ndjson,
+    /// A JSON array of logs.
+    JsonArray,
+    /// A JSON envelope, where the string `"{{logs}}"` is replaced with the array of logs.
+    JsonObject(Value),
+}
+
+impl BodyFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            BodyFormat::Ndjson => "application/x-ndjson",
+            _ => "application/json",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Http {
+    url: String,
+    method: Method,
+    content_type: String,
+    format: BodyFormat,
+    max_batch_bytes: usize,
+    max_batch_size: Option<usize>,
+    client: Client,
+}
+
+/// Fills in the envelope, `{{logs}}` and `{{count}}` replace the whole string they are in,
+/// the environment placeholders such as `{{function_name}}` are substituted within strings.
+fn render(template: &Value, logs: &[Value], environment: &HashMap<String, String>) -> Value {
+    match template {
+        Value::String(s) if s == LOGS_PLACEHOLDER => Value::Array(logs.to_vec()),
+        Value::String(s) if s == COUNT_PLACEHOLDER => Value::from(logs.len()),
+        Value::String(s) => Value::String(
+            environment
+                .iter()
+                .fold(s.clone(), |rendered, (placeholder, value)| {
+                    rendered.replace(placeholder.as_str(), value)
+                }),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| render(value, logs, environment))
+                .collect(),
+        ),
+        Value::Object(values) => Value::Object(
+            values
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value, logs, environment)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn environment() -> HashMap<String, String> {
+    [
+        ("{{function_name}}", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("{{function_version}}", "AWS_LAMBDA_FUNCTION_VERSION"),
+        ("{{region}}", "AWS_REGION"),
+    ]
+    .iter()
+    .filter_map(|(placeholder, var)| {
+        std::env::var(var)
+            .ok()
+            .map(|value| (placeholder.to_string(), value))
+    })
+    .collect()
+}
+
+pub fn from_env() -> Result<Http> {
+    let mut builder = Http::builder()
+        .with_url(get_required("HTTP_URL")?)
+        .with_timeout(get_timeout("HTTP_TIMEOUT"));
+    if let Ok(method) = std::env::var("HTTP_METHOD") {
+        builder = builder.with_method(Method::try_from(method.to_uppercase().as_str())?);
+    }
+    if let Ok(headers) = std::env::var("HTTP_HEADERS") {
+        let headers: HashMap<String, String> = serde_json::from_str(&headers)?;
+        for (name, value) in headers.iter() {
+            builder = builder.with_header(name, value)?;
+        }
+    }
+    if let Ok(content_type) = std::env::var("HTTP_CONTENT_TYPE") {
+        builder = builder.with_content_type(content_type);
+    }
+    if let Ok(format) = std::env::var("HTTP_FORMAT") {
+        builder = builder.with_format(match format.as_str() {
+            "ndjson" => BodyFormat::Ndjson,
+            "json_array" => BodyFormat::JsonArray,
+            "json_object" => {
+                BodyFormat::JsonObject(serde_json::from_str(&get_required("HTTP_ENVELOPE")?)?)
+            }
+            _ => return Err(Error::msg(format!("Unable to parse {} as Format", format))),
+        });
+    }
+    if let Ok(max_batch_bytes) = std::env::var("HTTP_MAX_BATCH_BYTES") {
+        builder = builder.with_max_batch_bytes(max_batch_bytes.parse()?);
+    }
+    if let Ok(max_batch_size) = std::env::var("HTTP_MAX_BATCH_SIZE") {
+        builder = builder.with_max_batch_size(max_batch_size.parse()?);
+    }
+    builder.build()
+}
+
+impl Http {
+    pub fn builder() -> HttpBuilder {
+        HttpBuilder::new()
+    }
+
+    fn payload(&self, logs: &[Log]) -> Result<String> {
+        let payload = match &self.format {
+            BodyFormat::Ndjson => logs
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join("\n"),
+            BodyFormat::JsonArray => format!(
+                "[{}]",
+                logs.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+            BodyFormat::JsonObject(template) => {
+                let values = logs
+                    .iter()
+                    .map(|log| match log {
+                        Log::Unformatted(data) => serde_json::to_value(data),
+                        Log::Formatted(data) => Ok(data.clone()),
+                    })
+                    .collect::<serde_json::Result<Vec<Value>>>()?;
+                render(template, &values, &environment()).to_string()
+            }
+        };
+        Ok(payload)
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let payload = self.payload(logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let res = self
+            .client
+            .request(self.method.clone(), &self.url)
+            .header(CONTENT_TYPE, &self.content_type)
+            .body(payload)
+            .send()
+            .await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogHandler for Http {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let max_batch_size = self.max_batch_size.unwrap_or(usize::MAX);
+        let chunks = local_logs
+            .byte_chunks_safe_mut(self.max_batch_bytes)
+            .flat_map(|chunk| chunk.chunks(max_batch_size));
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct HttpBuilder {
+    url: Option<String>,
+    method: Method,
+    headers: HeaderMap,
+    content_type: Option<String>,
+    format: BodyFormat,
+    max_batch_bytes: usize,
+    max_batch_size: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl HttpBuilder {
+    pub fn new() -> Self {
+        HttpBuilder {
+            url: None,
+            method: Method::POST,
+            headers: HeaderMap::new(),
+            content_type: None,
+            format: BodyFormat::Ndjson,
+            max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+            max_batch_size: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        self.headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+        Ok(self)
+    }
+
+    /// Overrides the content type implied by the body format.
+    pub fn with_content_type(mut self, content_type: String) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn with_format(mut self, format: BodyFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.max_batch_bytes = max_batch_bytes;
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Http> {
+        match self {
+            Self { url: Some(url), .. } => {
+                ensure!(
+                    self.max_batch_size != Some(0),
+                    "Max Batch Size must be positive"
+                );
+
+                let builder = Client::builder().default_headers(self.headers);
+                let client = match self.timeout {
+                    Some(duration) => builder.timeout(duration).build()?,
+                    None => builder.build()?,
+                };
+
+                let content_type = match self.content_type {
+                    Some(content_type) => content_type,
+                    None => self.format.content_type().to_string(),
+                };
+
+                Ok(Http {
+                    url,
+                    method: self.method,
+                    content_type,
+                    format: self.format,
+                    max_batch_bytes: self.max_batch_bytes,
+                    max_batch_size: self.max_batch_size,
+                    client,
+                })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BodyFormat, Http};
+    use crate::models::Log;
+
+    #[test]
+    fn renders_envelope() {
+        let http = Http::builder()
+            .with_url("http://localhost".to_string())
+            .with_format(BodyFormat::JsonObject(serde_json::json!({
+                "source": "woodchuck",
+                "count": "{{count}}",
+                "events": "{{logs}}"
+            })))
+            .build()
+            .unwrap();
+        let logs = vec![
+            Log::Formatted(serde_json::json!({ "data": "Hello" })),
+            Log::Formatted(serde_json::json!({ "data": "World" })),
+        ];
+
+        let payload: serde_json::Value =
+            serde_json::from_str(&http.payload(&logs).unwrap()).unwrap();
+
+        assert_eq!(payload["source"], "woodchuck");
+        assert_eq!(payload["count"], 2);
+        assert_eq!(payload["events"][1]["data"], "World");
+    }
+}
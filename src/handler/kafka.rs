@@ -0,0 +1,381 @@
+use crate::handler::{
+    get_required, FailedToSendLogsError, LogHandler, LogHandlerResponse, PermanentError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::convert::TryFrom;
+
+const DEFAULT_TIMEOUT: u64 = 5000;
+const COMPRESSION_TYPES: [&str; 5] = ["none", "gzip", "snappy", "lz4", "zstd"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKey {
+    RequestId,
+    FunctionName,
+}
+
+impl TryFrom<&str> for MessageKey {
+    type Error = anyhow::Error;
+    fn try_from(key: &str) -> Result<Self> {
+        match key {
+            "request_id" => Ok(MessageKey::RequestId),
+            "function_name" => Ok(MessageKey::FunctionName),
+            _ => Err(Error::msg(format!("Unable to parse {} as MessageKey", key))),
+        }
+    }
+}
+
+impl MessageKey {
+    /// The request id of the log, or the function name when it has none.
+    fn value(&self, log: &Log, function_name: &str) -> String {
+        let request_id = match self {
+            MessageKey::RequestId => log.request_id(),
+            MessageKey::FunctionName => None,
+        };
+        request_id.unwrap_or_else(|| function_name.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    Tls,
+    Sasl {
+        mechanism: String,
+        username: String,
+        password: String,
+    },
+}
+
+pub struct Kafka {
+    topic: String,
+    key: Option<MessageKey>,
+    function_name: String,
+    producer: FutureProducer,
+}
+
+pub fn from_env() -> Result<Kafka> {
+    let mut builder = Kafka::builder()
+        .with_brokers(get_required("KAFKA_BROKERS")?)
+        .with_topic(get_required("KAFKA_TOPIC")?);
+    if let Ok(key) = std::env::var("KAFKA_KEY") {
+        builder = builder.with_key(MessageKey::try_from(key.as_str())?);
+    }
+    if let Ok(compression) = std::env::var("KAFKA_COMPRESSION") {
+        builder = builder.with_compression(compression);
+    }
+    if let Ok(username) = std::env::var("KAFKA_SASL_USERNAME") {
+        builder = builder.with_auth(Auth::Sasl {
+            mechanism: std::env::var("KAFKA_SASL_MECHANISM")
+                .unwrap_or_else(|_| "SCRAM-SHA-512".to_string()),
+            username,
+            password: get_required("KAFKA_SASL_PASSWORD")?,
+        });
+    } else if std::env::var("KAFKA_TLS").as_deref() == Ok("true") {
+        builder = builder.with_auth(Auth::Tls);
+    }
+    if let Ok(ca_location) = std::env::var("KAFKA_SSL_CA_LOCATION") {
+        builder = builder.with_ca_location(ca_location);
+    }
+    if let Ok(timeout) = std::env::var("KAFKA_TIMEOUT") {
+        builder = builder.with_timeout(timeout.parse()?);
+    }
+    builder.build()
+}
+
+impl Kafka {
+    pub fn builder() -> KafkaBuilder {
+        KafkaBuilder::new()
+    }
+
+    fn message_key(&self, log: &Log) -> Option<String> {
+        self.key
+            .as_ref()
+            .map(|key| key.value(log, &self.function_name))
+    }
+}
+
+/// Marks errors caused by the message or the client's permissions as permanent, anything else
+/// such as a full queue or an unreachable broker is worth retrying.
+fn kafka_error(error: KafkaError) -> Error {
+    match error.rdkafka_error_code() {
+        Some(
+            RDKafkaErrorCode::InvalidMessage
+            | RDKafkaErrorCode::InvalidMessageSize
+            | RDKafkaErrorCode::MessageSizeTooLarge
+            | RDKafkaErrorCode::InvalidRecord
+            | RDKafkaErrorCode::InvalidTopic
+            | RDKafkaErrorCode::PolicyViolation
+            | RDKafkaErrorCode::TopicAuthorizationFailed
+            | RDKafkaErrorCode::ClusterAuthorizationFailed
+            | RDKafkaErrorCode::SaslAuthenticationFailed
+            | RDKafkaErrorCode::Authentication,
+        ) => PermanentError(error.to_string()).into(),
+        _ => error.into(),
+    }
+}
+
+#[async_trait]
+impl LogHandler for Kafka {
+    /// Queues every log with the producer and waits for each delivery report before returning
+    /// so nothing is left in the producer's buffer when the Lambda environment is frozen. The
+    /// reports resolve within the message timeout, delivered or not.
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+        let mut deliveries = Vec::new();
+
+        for log in logs.into_iter() {
+            let payload = log.to_string();
+            let key = self.message_key(&log);
+            let mut record = FutureRecord::to(&self.topic).payload(&payload);
+            if let Some(key) = &key {
+                record = record.key(key);
+            }
+            match self.producer.send_result(record) {
+                Ok(delivery) => deliveries.push((log, delivery)),
+                Err((e, _)) => {
+                    let e = kafka_error(e);
+                    log::error!("{}", e);
+                    failed_to_send_logs.add(&[log], &e);
+                }
+            }
+        }
+
+        log::debug!("Awaiting {} deliveries", deliveries.len());
+
+        let (logs, deliveries): (Vec<Log>, Vec<_>) = deliveries.into_iter().unzip();
+        for (log, delivery) in logs.into_iter().zip(join_all(deliveries).await) {
+            match delivery {
+                Ok(Ok(_)) => {}
+                Ok(Err((e, _))) => {
+                    let e = kafka_error(e);
+                    log::error!("{}", e);
+                    failed_to_send_logs.add(&[log], &e);
+                }
+                Err(_) => {
+                    log::error!("Delivery Cancelled");
+                    failed_to_send_logs.logs.push(log);
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct KafkaBuilder {
+    brokers: Option<String>,
+    topic: Option<String>,
+    key: Option<MessageKey>,
+    compression: String,
+    auth: Option<Auth>,
+    ca_location: Option<String>,
+    timeout: u64,
+}
+
+impl KafkaBuilder {
+    pub fn new() -> Self {
+        KafkaBuilder {
+            brokers: None,
+            topic: None,
+            key: None,
+            compression: "none".to_string(),
+            auth: None,
+            ca_location: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// A comma separated list of `host:port` bootstrap brokers.
+    pub fn with_brokers(mut self, brokers: String) -> Self {
+        self.brokers = Some(brokers);
+        self
+    }
+
+    pub fn with_topic(mut self, topic: String) -> Self {
+        self.topic = Some(topic);
+        self
+    }
+
+    /// Messages are sent without a key unless one is chosen.
+    pub fn with_key(mut self, key: MessageKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// One of `none`, `gzip`, `snappy`, `lz4` or `zstd`.
+    pub fn with_compression(mut self, compression: String) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    pub fn with_ca_location(mut self, ca_location: String) -> Self {
+        self.ca_location = Some(ca_location);
+        self
+    }
+
+    /// How long in milliseconds to wait for messages to be delivered.
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let brokers = self
+            .brokers
+            .as_ref()
+            .ok_or_else(|| Error::msg("Brokers Required"))?;
+        ensure!(
+            COMPRESSION_TYPES.contains(&self.compression.as_str()),
+            "Invalid Compression {}",
+            self.compression
+        );
+
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", brokers)
+            .set("compression.type", &self.compression)
+            .set("message.timeout.ms", self.timeout.to_string())
+            .set("linger.ms", "5");
+        match &self.auth {
+            Some(Auth::Tls) => {
+                config.set("security.protocol", "ssl");
+            }
+            Some(Auth::Sasl {
+                mechanism,
+                username,
+                password,
+            }) => {
+                config
+                    .set("security.protocol", "sasl_ssl")
+                    .set("sasl.mechanisms", mechanism)
+                    .set("sasl.username", username)
+                    .set("sasl.password", password);
+            }
+            None => {}
+        }
+        if let Some(ca_location) = &self.ca_location {
+            config.set("ssl.ca.location", ca_location);
+        }
+        Ok(config)
+    }
+
+    pub fn build(self) -> Result<Kafka> {
+        let config = self.client_config()?;
+        match self {
+            Self {
+                topic: Some(topic), ..
+            } => Ok(Kafka {
+                topic,
+                key: self.key,
+                function_name: std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                    .unwrap_or_else(|_| "woodchuck".to_string()),
+                producer: config.create()?,
+            }),
+            Self { topic: None, .. } => Err(Error::msg("Topic Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{kafka_error, Auth, KafkaBuilder, MessageKey};
+    use crate::handler::is_retryable;
+    use crate::models::{Log, StructuredLog};
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+
+    #[test]
+    fn keys_messages() {
+        let unformatted = Log::Unformatted(StructuredLog {
+            timestamp: None,
+            guid: Some("6e48723a-1596-4313-a9af-e4da9214d637".to_string()),
+            level: None,
+            data: serde_json::json!("Hello World"),
+            trace_id: None,
+            span_id: None,
+        });
+        let formatted = Log::Formatted(serde_json::json!({
+            "awsRequestId": "79b4f56e-95b1-4643-9700-2807f4e68189",
+            "message": "Hello World"
+        }));
+        let anonymous = Log::Formatted(serde_json::json!({ "message": "Hello World" }));
+
+        assert_eq!(
+            MessageKey::RequestId.value(&unformatted, "function"),
+            "6e48723a-1596-4313-a9af-e4da9214d637"
+        );
+        assert_eq!(
+            MessageKey::RequestId.value(&formatted, "function"),
+            "79b4f56e-95b1-4643-9700-2807f4e68189"
+        );
+        assert_eq!(
+            MessageKey::RequestId.value(&anonymous, "function"),
+            "function"
+        );
+        assert_eq!(
+            MessageKey::FunctionName.value(&formatted, "function"),
+            "function"
+        );
+    }
+
+    #[test]
+    fn configures_client() {
+        let config = KafkaBuilder::new()
+            .with_brokers("broker-1:9096,broker-2:9096".to_string())
+            .with_compression("zstd".to_string())
+            .with_auth(Auth::Sasl {
+                mechanism: "SCRAM-SHA-512".to_string(),
+                username: "woodchuck".to_string(),
+                password: "secret".to_string(),
+            })
+            .with_ca_location("/etc/ssl/certs/ca.pem".to_string())
+            .with_timeout(2000)
+            .client_config()
+            .unwrap();
+
+        assert_eq!(
+            config.get("bootstrap.servers"),
+            Some("broker-1:9096,broker-2:9096")
+        );
+        assert_eq!(config.get("compression.type"), Some("zstd"));
+        assert_eq!(config.get("message.timeout.ms"), Some("2000"));
+        assert_eq!(config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(config.get("sasl.mechanisms"), Some("SCRAM-SHA-512"));
+        assert_eq!(config.get("sasl.username"), Some("woodchuck"));
+        assert_eq!(config.get("ssl.ca.location"), Some("/etc/ssl/certs/ca.pem"));
+
+        let config = KafkaBuilder::new()
+            .with_brokers("broker:9094".to_string())
+            .with_auth(Auth::Tls)
+            .client_config()
+            .unwrap();
+        assert_eq!(config.get("security.protocol"), Some("ssl"));
+        assert_eq!(config.get("sasl.username"), None);
+
+        assert!(KafkaBuilder::new().client_config().is_err());
+        assert!(KafkaBuilder::new()
+            .with_brokers("broker:9092".to_string())
+            .with_compression("brotli".to_string())
+            .client_config()
+            .is_err());
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let retryable = |code| is_retryable(&kafka_error(KafkaError::MessageProduction(code)));
+
+        assert!(!retryable(RDKafkaErrorCode::MessageSizeTooLarge));
+        assert!(!retryable(RDKafkaErrorCode::TopicAuthorizationFailed));
+        assert!(retryable(RDKafkaErrorCode::QueueFull));
+        assert!(retryable(RDKafkaErrorCode::MessageTimedOut));
+    }
+}
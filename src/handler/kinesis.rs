@@ -0,0 +1,223 @@
+use crate::handler::{
+    aws_error, get_required, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use rusoto_core::Region;
+use rusoto_kinesis::{
+    Kinesis as KinesisApi, KinesisClient, PutRecordsError, PutRecordsInput, PutRecordsRequestEntry,
+};
+use std::convert::TryFrom;
+
+const MAX_RECORDS: usize = 500;
+const MAX_REQUEST_BYTES: usize = 4900000; //5MB per request, give ourselves 100kb overhead for partition keys.
+const MAX_PARTITION_KEY_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionKey {
+    RequestId,
+    FunctionName,
+    Field(String),
+}
+
+impl TryFrom<&str> for PartitionKey {
+    type Error = anyhow::Error;
+    fn try_from(key: &str) -> Result<Self> {
+        match key {
+            "request_id" => Ok(PartitionKey::RequestId),
+            "function_name" => Ok(PartitionKey::FunctionName),
+            _ if key.starts_with("field:") => Ok(PartitionKey::Field(key[6..].to_string())),
+            _ => Err(Error::msg(format!(
+                "Unable to parse {} as PartitionKey",
+                key
+            ))),
+        }
+    }
+}
+
+pub struct Kinesis {
+    stream_name: String,
+    partition_key: PartitionKey,
+    function_name: String,
+    client: KinesisClient,
+}
+
+pub fn from_env() -> Result<Kinesis> {
+    let partition_key = match std::env::var("WOODCHUCK_KINESIS_PARTITION_KEY") {
+        Ok(key) => PartitionKey::try_from(key.as_str())?,
+        Err(_) => PartitionKey::RequestId,
+    };
+    Ok(Kinesis::new(
+        get_required("WOODCHUCK_KINESIS_TARGET")?,
+        partition_key,
+    ))
+}
+
+impl Kinesis {
+    pub fn new(stream_name: String, partition_key: PartitionKey) -> Self {
+        Kinesis {
+            stream_name,
+            partition_key,
+            function_name: std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                .unwrap_or_else(|_| "woodchuck".to_string()),
+            client: KinesisClient::new(Region::default()),
+        }
+    }
+
+    /// Picks the partition key for a log. Logs without a request id get a random key so they
+    /// spread across shards, otherwise it falls back to the function name when the log has no
+    /// field to partition by.
+    fn partition_key(&self, log: &Log) -> String {
+        let key = match &self.partition_key {
+            PartitionKey::RequestId => Some(
+                log.request_id()
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            ),
+            PartitionKey::FunctionName => None,
+            PartitionKey::Field(field) => log.field(field).map(|value| match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }),
+        };
+        match key {
+            Some(key) if !key.is_empty() => key.chars().take(MAX_PARTITION_KEY_LENGTH).collect(),
+            _ => self.function_name.clone(),
+        }
+    }
+
+    /// Returns the logs whose records were rejected, the rest were written to the stream.
+    async fn send_logs(&self, logs: &[Log]) -> Result<Vec<Log>> {
+        let records: Vec<PutRecordsRequestEntry> = logs
+            .iter()
+            .map(|log| PutRecordsRequestEntry {
+                data: log.to_string().into(),
+                partition_key: self.partition_key(log),
+                ..Default::default()
+            })
+            .collect();
+
+        let input = PutRecordsInput {
+            stream_name: self.stream_name.clone(),
+            records,
+        };
+
+        let output = self.client.put_records(input).await.map_err(|e| {
+            aws_error(e, |e| {
+                !matches!(
+                    e,
+                    PutRecordsError::KMSThrottling(_)
+                        | PutRecordsError::ProvisionedThroughputExceeded(_)
+                )
+            })
+        })?;
+
+        match output.failed_record_count {
+            Some(0) | None => Ok(Vec::new()),
+            Some(count) => {
+                ensure!(
+                    output.records.len() == logs.len(),
+                    "PutRecords response has {} records for {} logs",
+                    output.records.len(),
+                    logs.len()
+                );
+                log::debug!("{} records failed", count);
+                Ok(logs
+                    .iter()
+                    .zip(output.records.iter())
+                    .filter(|(_, result)| result.error_code.is_some())
+                    .map(|(log, result)| {
+                        log::error!("{:?}: {:?}", result.error_code, result.error_message);
+                        log.clone()
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for Kinesis {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs
+            .byte_chunks_safe_mut(MAX_REQUEST_BYTES)
+            .flat_map(|chunk| chunk.chunks(MAX_RECORDS));
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                Ok(failed) => {
+                    log::debug!(
+                        "Sent Chunk {} with {} items, {} failed.",
+                        index,
+                        chunk.len(),
+                        failed.len()
+                    );
+                    failed_to_send_logs.logs.extend(failed);
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kinesis, PartitionKey};
+    use crate::models::{Log, StructuredLog};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn parses_partition_key() {
+        assert_eq!(
+            PartitionKey::try_from("request_id").unwrap(),
+            PartitionKey::RequestId
+        );
+        assert_eq!(
+            PartitionKey::try_from("field:tenant").unwrap(),
+            PartitionKey::Field("tenant".to_string())
+        );
+        assert!(PartitionKey::try_from("tenant").is_err());
+    }
+
+    #[test]
+    fn partitions_by_request_id() {
+        let kinesis = Kinesis::new("stream".to_string(), PartitionKey::RequestId);
+        let unformatted = Log::Unformatted(StructuredLog {
+            timestamp: None,
+            guid: Some("6e48723a-1596-4313-a9af-e4da9214d637".to_string()),
+            level: None,
+            data: serde_json::json!("Hello World"),
+            trace_id: None,
+            span_id: None,
+        });
+        let formatted = Log::Formatted(serde_json::json!({
+            "requestId": "79b4f56e-95b1-4643-9700-2807f4e68189",
+            "message": "Hello World"
+        }));
+        let anonymous = Log::Formatted(serde_json::json!({ "message": "Hello World" }));
+
+        assert_eq!(
+            kinesis.partition_key(&unformatted),
+            "6e48723a-1596-4313-a9af-e4da9214d637"
+        );
+        assert_eq!(
+            kinesis.partition_key(&formatted),
+            "79b4f56e-95b1-4643-9700-2807f4e68189"
+        );
+        assert_ne!(
+            kinesis.partition_key(&anonymous),
+            kinesis.partition_key(&anonymous)
+        );
+    }
+}
@@ -1,10 +1,12 @@
-use crate::handler::{LogHandler, LogHandlerResponse};
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
 use crate::models::Log;
 use anyhow::{ensure, Error, Result};
 use async_trait::async_trait;
 use byte_chunk::SafeByteChunkedMut;
 use reqwest::header::CONTENT_TYPE;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use std::time::Duration;
 
 #[derive(Debug, Clone)]
@@ -13,6 +15,14 @@ pub struct Loggly {
     client: Client,
 }
 
+pub fn from_env() -> Result<Loggly> {
+    Loggly::builder()
+        .with_token(get_required("LOGGLY_TOKEN")?)
+        .with_tag(get_required("LOGGLY_TAG")?)
+        .with_timeout(get_timeout("LOGGLY_TIMEOUT"))
+        .build()
+}
+
 impl Loggly {
     pub fn builder() -> LogglyBuilder {
         LogglyBuilder::new()
@@ -41,10 +51,7 @@ impl Loggly {
 
         println!("Response: Status:{}", &res.status());
 
-        ensure!(
-            res.status() == reqwest::StatusCode::OK,
-            "Error Sending Logs"
-        );
+        ensure!(res.status() == StatusCode::OK, StatusError(res.status()));
 
         Ok(())
     }
@@ -56,24 +63,21 @@ impl LogHandler for Loggly {
         let mut local_logs = logs.to_owned();
         let chunks = local_logs.byte_chunks_safe_mut(4900000); //give ourselves 100kb overhead to be safe.
 
-        let mut failed_to_send_logs = Vec::<Log>::new();
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
 
         for (index, chunk) in chunks.enumerate() {
             let rslt = self.send_logs(chunk).await;
             match rslt {
                 Err(e) => {
                     log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
-                    failed_to_send_logs.extend_from_slice(chunk);
+                    failed_to_send_logs.add(chunk, &e);
                     log::error!("{}", e)
                 }
                 _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
             }
         }
 
-        match failed_to_send_logs.len() {
-            0 => Ok(()),
-            _ => Err(failed_to_send_logs.into()),
-        }
+        failed_to_send_logs.into_response()
     }
 }
 
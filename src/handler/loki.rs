@@ -0,0 +1,411 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::Utc;
+use prost::Message;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const TENANT_HEADER: &str = "X-Scope-OrgID";
+const DEFAULT_LABELS: &str = "function_name,level";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Label {
+    FunctionName,
+    Level,
+    Region,
+    Version,
+}
+
+impl TryFrom<&str> for Label {
+    type Error = anyhow::Error;
+    fn try_from(label: &str) -> Result<Self> {
+        match label {
+            "function_name" => Ok(Label::FunctionName),
+            "level" => Ok(Label::Level),
+            "region" => Ok(Label::Region),
+            "version" => Ok(Label::Version),
+            _ => Err(Error::msg(format!("Unable to parse {} as Label", label))),
+        }
+    }
+}
+
+impl Label {
+    fn name(&self) -> &'static str {
+        match self {
+            Label::FunctionName => "function_name",
+            Label::Level => "level",
+            Label::Region => "region",
+            Label::Version => "version",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    Json,
+    Protobuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Loki {
+    url: String,
+    labels: Vec<Label>,
+    environment: BTreeMap<&'static str, String>,
+    format: Format,
+    tenant: Option<String>,
+    basic_auth: Option<(String, String)>,
+    client: Client,
+}
+
+type Labels = BTreeMap<&'static str, String>;
+/// Lines with their timestamps in nanoseconds.
+type Stream = (Labels, Vec<(u64, String)>);
+
+#[derive(Clone, PartialEq, Message)]
+struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    labels: String,
+    #[prost(message, repeated, tag = "2")]
+    entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    timestamp: Option<prost_types::Timestamp>,
+    #[prost(string, tag = "2")]
+    line: String,
+}
+
+pub fn from_env() -> Result<Loki> {
+    let labels = std::env::var("LOKI_LABELS").unwrap_or_else(|_| DEFAULT_LABELS.to_string());
+    let mut builder = Loki::builder()
+        .with_url(get_required("LOKI_URL")?)
+        .with_labels(
+            labels
+                .split(',')
+                .map(|label| Label::try_from(label.trim()))
+                .collect::<Result<Vec<Label>>>()?,
+        )
+        .with_timeout(get_timeout("LOKI_TIMEOUT"));
+    if let Ok(format) = std::env::var("LOKI_FORMAT") {
+        builder = builder.with_format(match format.as_str() {
+            "json" => Format::Json,
+            "protobuf" => Format::Protobuf,
+            _ => return Err(Error::msg(format!("Unable to parse {} as Format", format))),
+        });
+    }
+    if let Ok(tenant) = std::env::var("LOKI_TENANT_ID") {
+        builder = builder.with_tenant(tenant);
+    }
+    if let (Ok(username), Ok(password)) = (
+        std::env::var("LOKI_USERNAME"),
+        std::env::var("LOKI_PASSWORD"),
+    ) {
+        builder = builder.with_basic_auth(username, password);
+    }
+    builder.build()
+}
+
+impl Loki {
+    pub fn builder() -> LokiBuilder {
+        LokiBuilder::new()
+    }
+
+    /// Groups logs into streams by their label values, ordering each stream by timestamp.
+    fn streams(&self, logs: &[Log]) -> Vec<Stream> {
+        let mut streams: BTreeMap<Labels, Vec<(u64, String)>> = BTreeMap::new();
+        for log in logs.iter() {
+            let labels = self
+                .labels
+                .iter()
+                .map(|label| {
+                    let value = match label {
+                        Label::Level => log
+                            .level()
+                            .map(|level| format!("{:?}", level).to_lowercase()),
+                        _ => self.environment.get(label.name()).cloned(),
+                    };
+                    (label.name(), value.unwrap_or_else(|| "unknown".to_string()))
+                })
+                .collect();
+            let timestamp = log
+                .timestamp_nanos()
+                .unwrap_or_else(|| Utc::now().timestamp_nanos() as u64);
+            streams
+                .entry(labels)
+                .or_default()
+                .push((timestamp, log.to_string()));
+        }
+        streams
+            .into_iter()
+            .map(|(labels, mut entries)| {
+                entries.sort_by_key(|(timestamp, _)| *timestamp);
+                (labels, entries)
+            })
+            .collect()
+    }
+
+    fn json_payload(streams: Vec<Stream>) -> Result<Vec<u8>> {
+        let streams: Vec<serde_json::Value> = streams
+            .into_iter()
+            .map(|(labels, entries)| {
+                serde_json::json!({
+                    "stream": labels,
+                    "values": entries
+                        .into_iter()
+                        .map(|(timestamp, line)| vec![timestamp.to_string(), line])
+                        .collect::<Vec<Vec<String>>>(),
+                })
+            })
+            .collect();
+        Ok(serde_json::to_vec(
+            &serde_json::json!({ "streams": streams }),
+        )?)
+    }
+
+    fn protobuf_payload(streams: Vec<Stream>) -> Result<Vec<u8>> {
+        let request = PushRequest {
+            streams: streams
+                .into_iter()
+                .map(|(labels, entries)| StreamAdapter {
+                    labels: format!(
+                        "{{{}}}",
+                        labels
+                            .iter()
+                            .map(|(name, value)| format!("{}={:?}", name, value))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                    entries: entries
+                        .into_iter()
+                        .map(|(timestamp, line)| EntryAdapter {
+                            timestamp: Some(prost_types::Timestamp {
+                                seconds: (timestamp / 1_000_000_000) as i64,
+                                nanos: (timestamp % 1_000_000_000) as i32,
+                            }),
+                            line,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let streams = self.streams(logs);
+        let (payload, content_type) = match self.format {
+            Format::Json => (Self::json_payload(streams)?, "application/json"),
+            Format::Protobuf => (Self::protobuf_payload(streams)?, "application/x-protobuf"),
+        };
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, content_type)
+            .body(payload);
+        let request = match &self.tenant {
+            Some(tenant) => request.header(TENANT_HEADER, tenant),
+            None => request,
+        };
+        let request = match &self.basic_auth {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        };
+        let res = request.send().await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogHandler for Loki {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(4000000); //Loki's default grpc_server_max_recv_msg_size is 4MB.
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct LokiBuilder {
+    url: Option<String>,
+    labels: Vec<Label>,
+    format: Format,
+    tenant: Option<String>,
+    basic_auth: Option<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl LokiBuilder {
+    pub fn new() -> Self {
+        LokiBuilder {
+            url: None,
+            labels: vec![Label::FunctionName, Label::Level],
+            format: Format::Protobuf,
+            tenant: None,
+            basic_auth: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_labels(mut self, labels: Vec<Label>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: String) -> Self {
+        self.tenant = Some(tenant);
+        self
+    }
+
+    pub fn with_basic_auth(mut self, username: String, password: String) -> Self {
+        self.basic_auth = Some((username, password));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Loki> {
+        match self {
+            Self { url: Some(url), .. } => {
+                let client = match self.timeout {
+                    Some(duration) => Client::builder().timeout(duration).build()?,
+                    None => Client::builder().build()?,
+                };
+
+                let mut environment = BTreeMap::new();
+                for (label, var) in [
+                    (Label::FunctionName, "AWS_LAMBDA_FUNCTION_NAME"),
+                    (Label::Region, "AWS_REGION"),
+                    (Label::Version, "AWS_LAMBDA_FUNCTION_VERSION"),
+                ]
+                .iter()
+                {
+                    if let Ok(value) = std::env::var(var) {
+                        environment.insert(label.name(), value);
+                    }
+                }
+
+                Ok(Loki {
+                    url: format!("{}/loki/api/v1/push", url.trim_end_matches('/')),
+                    labels: self.labels,
+                    environment,
+                    format: self.format,
+                    tenant: self.tenant,
+                    basic_auth: self.basic_auth,
+                    client,
+                })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Label, Loki};
+    use crate::models::Log;
+
+    #[test]
+    fn groups_streams_in_timestamp_order() {
+        let loki = Loki::builder()
+            .with_url("http://localhost:3100".to_string())
+            .with_labels(vec![Label::Level])
+            .build()
+            .unwrap();
+        let logs = vec![
+            Log::Formatted(
+                serde_json::json!({ "level": "info", "timestamp": "2020-11-18T23:52:31.000Z" }),
+            ),
+            Log::Formatted(
+                serde_json::json!({ "level": "error", "timestamp": "2020-11-18T23:52:30.000Z" }),
+            ),
+            Log::Formatted(
+                serde_json::json!({ "level": "info", "timestamp": "2020-11-18T23:52:30.000Z" }),
+            ),
+        ];
+
+        let streams = loki.streams(&logs);
+
+        assert_eq!(streams.len(), 2);
+        let (labels, entries) = &streams[1];
+        assert_eq!(labels["level"], "info");
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].0 < entries[1].0);
+    }
+
+    #[test]
+    fn replaces_out_of_range_timestamps() {
+        let loki = Loki::builder()
+            .with_url("http://localhost:3100".to_string())
+            .with_labels(vec![Label::Level])
+            .build()
+            .unwrap();
+        let logs = vec![
+            Log::Formatted(serde_json::json!({ "timestamp": "0001-01-01T00:00:00Z" })),
+            Log::Formatted(serde_json::json!({ "timestamp": "1969-12-31T23:59:59Z" })),
+            Log::Formatted(serde_json::json!({ "timestamp": "9999-12-31T23:59:59Z" })),
+        ];
+
+        let streams = loki.streams(&logs);
+
+        let year_2020 = 1577836800000000000;
+        assert!(streams[0]
+            .1
+            .iter()
+            .all(|(timestamp, _)| *timestamp > year_2020));
+    }
+}
@@ -1,19 +1,185 @@
+use crate::extension::retry::RetryPolicy;
 use crate::models::Log;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use async_trait::async_trait;
+use reqwest::StatusCode;
+use rusoto_core::RusotoError;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+mod connection;
+mod cloudwatch;
+mod custom;
+mod datadog;
+mod dead_letter;
+mod elasticsearch;
+mod fanout;
+mod firehose;
+mod fluentd;
+mod gelf;
+mod http;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod kinesis;
+mod loggly;
+mod logzio;
+mod loki;
+mod newrelic;
+mod otlp;
+mod router;
+mod s3;
+mod splunk;
+mod spool;
+mod sqs;
+mod sumologic;
+mod syslog;
+
 const DEFAULT_TIMEOUT: u64 = 1000;
 
-#[derive(Debug)]
+pub const DESTINATION_ENV: &str = "WOODCHUCK_DESTINATION";
+pub const ROUTES_ENV: &str = "WOODCHUCK_ROUTES";
+pub const SPOOL_ENV: &str = "WOODCHUCK_SPOOL";
+pub const DEAD_LETTER_ENV: &str = "WOODCHUCK_DEAD_LETTER";
+
+pub use dead_letter::DeadLetter;
+
+#[derive(Debug, Default)]
 pub struct FailedToSendLogsError {
+    /// Logs that may still be delivered by sending them again.
     pub logs: Vec<Log>,
+    /// Logs the destination refused, sending them again will fail the same way.
+    pub rejected: Vec<Log>,
+    /// The last error sending failed with, when the handler reported one.
+    pub reason: Option<String>,
+}
+
+impl FailedToSendLogsError {
+    /// Records logs that failed to send with `error`, sorting them by whether to retry.
+    pub fn add(&mut self, logs: &[Log], error: &Error) {
+        match is_retryable(error) {
+            true => self.logs.extend_from_slice(logs),
+            false => self.rejected.extend_from_slice(logs),
+        }
+        self.reason = Some(error.to_string());
+    }
+
+    pub fn extend(&mut self, other: FailedToSendLogsError) {
+        self.logs.extend(other.logs);
+        self.rejected.extend(other.rejected);
+        if other.reason.is_some() {
+            self.reason = other.reason;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.logs.len() + self.rejected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_response(self) -> LogHandlerResponse {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
 }
 
 impl From<Vec<Log>> for FailedToSendLogsError {
     fn from(logs: Vec<Log>) -> Self {
-        FailedToSendLogsError { logs }
+        FailedToSendLogsError {
+            logs,
+            ..Default::default()
+        }
+    }
+}
+
+/// The unsuccessful status a destination responded with.
+#[derive(Debug)]
+pub struct StatusError(pub StatusCode);
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Error Sending Logs: Status:{}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+impl StatusError {
+    /// Timeouts, throttling and server errors are worth retrying, other client errors such as
+    /// 400, 401 and 403 are not.
+    pub fn is_retryable(&self) -> bool {
+        match self.0 {
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => true,
+            status => !status.is_client_error(),
+        }
+    }
+}
+
+/// An error that sending the logs again will fail with too, such as a missing resource or an
+/// invalid request.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl std::fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// Error codes some AWS services send with a client error status when a request was throttled.
+const THROTTLING_CODES: [&str; 4] = [
+    "Throttl",
+    "SlowDown",
+    "RequestLimitExceeded",
+    "TooManyRequests",
+];
+
+/// Converts an AWS error, marking it permanent when it is a validation error, denied
+/// credentials, one of the service's own errors picked out by `permanent`, or an unmodelled
+/// error with a status that is not worth retrying.
+pub fn aws_error<E>(error: RusotoError<E>, permanent: fn(&E) -> bool) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match error {
+        RusotoError::Service(e) if permanent(&e) => PermanentError(e.to_string()).into(),
+        RusotoError::Validation(message) => PermanentError(message).into(),
+        RusotoError::Credentials(e) if e.message.contains("AccessDenied") => {
+            PermanentError(e.message).into()
+        }
+        RusotoError::Unknown(response) => {
+            let body = String::from_utf8_lossy(&response.body).to_string();
+            let throttled = THROTTLING_CODES.iter().any(|code| body.contains(code));
+            match throttled || StatusError(response.status).is_retryable() {
+                true => RusotoError::<E>::Unknown(response).into(),
+                false => PermanentError(format!("{}: {}", response.status, body)).into(),
+            }
+        }
+        e => e.into(),
+    }
+}
+
+/// Whether logs that failed with `error` may be delivered by sending them again, errors
+/// without a status such as timeouts and connection failures are assumed to be transient.
+pub fn is_retryable(error: &Error) -> bool {
+    if let Some(status) = error.downcast_ref::<StatusError>() {
+        return status.is_retryable();
+    }
+    if error.downcast_ref::<PermanentError>().is_some() {
+        return false;
+    }
+    match error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+    {
+        Some(status) => StatusError(status).is_retryable(),
+        None => true,
     }
 }
 
@@ -22,86 +188,233 @@ pub type LogHandlerResponse = Result<(), FailedToSendLogsError>;
 #[async_trait]
 pub trait LogHandler {
     async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse;
+
+    /// Sends any logs the handler is holding on to, called before the extension shuts down.
+    async fn flush(&self) -> LogHandlerResponse {
+        Ok(())
+    }
+
+    /// Sends any logs the handler has held on to for longer than it should, called regularly
+    /// by the background flusher so held logs go out even when no new logs arrive.
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        Ok(())
+    }
+}
+
+/// Combines the responses of several handlers, failing with all of their failed logs.
+fn merge_responses(results: Vec<LogHandlerResponse>) -> LogHandlerResponse {
+    let mut failed_to_send_logs = FailedToSendLogsError::default();
+    for result in results.into_iter() {
+        if let Err(failed) = result {
+            failed_to_send_logs.extend(failed);
+        }
+    }
+    failed_to_send_logs.into_response()
+}
+
+#[async_trait]
+impl<T: LogHandler + Sync + Send + ?Sized> LogHandler for Box<T> {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        (**self).handle_logs(logs).await
+    }
+
+    async fn flush(&self) -> LogHandlerResponse {
+        (**self).flush().await
+    }
+
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        (**self).flush_expired().await
+    }
 }
 
 pub type Handler = Arc<RwLock<dyn LogHandler + Sync + Send>>;
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "loggly")] {
-        mod loggly;
-        pub fn get_default() -> Result<Handler> {
-            let token = std::env::var("LOGGLY_TOKEN").unwrap();
-            let tag = std::env::var("LOGGLY_TAG").unwrap();
-            let timeout: Option<u64> = match std::env::var("LOGGLY_TIMEOUT") {
-                Ok(data) => match data.parse() {
-                    Ok(0) => {
-                        println!("LOGGLY_TIMEOUT set to Infinite");
-                        None
-                    }
-                    Ok(t) => {
-                        println!("LOGGLY_TIMEOUT set to {}ms", &t);
-                        Some(t)
-                    }
-                    Err(_) => {
-                        println!("LOGGLY_TIMEOUT: Cannot be parsed from {}", data);
-                        Some(DEFAULT_TIMEOUT)
-                    }
-                },
-                Err(_) => Some(DEFAULT_TIMEOUT),
-            };
-
-            Ok(Arc::new(RwLock::new(
-                loggly::Loggly::builder()
-                    .with_token(token)
-                    .with_tag(tag)
-                    .with_timeout(timeout)
-                    .build()?,
-            )))
+pub type BoxedLogHandler = Box<dyn LogHandler + Sync + Send>;
+
+/// The destination configured through `WOODCHUCK_DESTINATION`, or `custom` when unset.
+/// Several destinations can be given as a comma separated list, e.g. `loggly,logzio`.
+pub fn get_destination() -> String {
+    match std::env::var(DESTINATION_ENV) {
+        Ok(destination) if !destination.trim().is_empty() => destination.trim().to_lowercase(),
+        _ => "custom".to_string(),
+    }
+}
+
+pub fn get_default() -> Result<Handler> {
+    let destination = get_destination();
+    println!("{} set to {}", DESTINATION_ENV, &destination);
+    let handler: BoxedLogHandler = match std::env::var(ROUTES_ENV) {
+        Ok(routes) => {
+            let routes = router::routes_from_str(&routes)?;
+            println!("{} set with {} routes", ROUTES_ENV, routes.len());
+            Box::new(router::Router::new(routes, destination)?)
         }
-    } else if #[cfg(feature = "logzio")] {
-        mod logzio;
-        pub fn get_default() -> Result<Handler> {
-            let token = std::env::var("LOGZIO_TOKEN").unwrap();
-            let host = std::env::var("LOGZIO_HOST").unwrap();
-            let timeout: Option<u64> = match std::env::var("LOGZIO_TIMEOUT") {
-                Ok(data) => match data.parse() {
-                    Ok(0) => {
-                        println!("LOGZIO_TIMEOUT set to Infinite");
-                        None
-                    }
-                    Ok(t) => {
-                        println!("LOGZIO_TIMEOUT set to {}ms", &t);
-                        Some(t)
-                    }
-                    Err(_) => {
-                        println!("LOGZIO_TIMEOUT: Cannot be parsed from {}", data);
-                        Some(DEFAULT_TIMEOUT)
-                    }
-                },
-                Err(_) => Some(DEFAULT_TIMEOUT),
-            };
-
-            Ok(Arc::new(RwLock::new(
-                logzio::Logzio::builder()
-                    .with_token(token)
-                    .with_host(host)
-                    .with_timeout(timeout)
-                    .build()?,
-            )))
+        Err(_) => build_handler(&destination)?,
+    };
+    match std::env::var(SPOOL_ENV) {
+        Ok(spool) if spool.trim().to_lowercase() == "true" => {
+            println!("{} set, spooling failed logs to disk", SPOOL_ENV);
+            Ok(Arc::new(RwLock::new(spool::Spooled::new(
+                handler,
+                spool::from_env()?,
+                RetryPolicy::default(),
+            ))))
         }
-    } else if #[cfg(feature = "firehose")] {
-        mod firehose;
-        pub fn get_default() -> Result<Handler> {
-            let stream = std::env::var("WOODCHUCK_FIREHOSE_TARGET").unwrap();
-            let metadata = serde_json::from_str((std::env::var("WOODCHUCK_FIREHOSE_METADATA")?).as_ref())?;
-            Ok(Arc::new(RwLock::new(
-                firehose::Firehose::new(stream, metadata)
+        _ => Ok(Arc::new(RwLock::new(handler))),
+    }
+}
+
+/// Where logs go once retrying them is given up, when configured.
+pub fn get_dead_letter() -> Result<Option<Arc<DeadLetter>>> {
+    Ok(dead_letter::from_env()?.map(Arc::new))
+}
+
+pub fn build_handler(destination: &str) -> Result<BoxedLogHandler> {
+    let destinations: Vec<&str> = destination
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .collect();
+    match destinations.as_slice() {
+        [single] => build_single_handler(single),
+        _ => {
+            let handlers = destinations
+                .iter()
+                .map(|d| Ok((d.to_string(), build_single_handler(d)?)))
+                .collect::<Result<Vec<(String, BoxedLogHandler)>>>()?;
+            Ok(Box::new(fanout::FanOut::new(
+                handlers,
+                RetryPolicy::default(),
             )))
         }
-    } else {
-        mod custom;
-        pub fn get_default() -> Result<Handler> {
-            Ok(Arc::new(RwLock::new(custom::Custom::new())))
-        }
+    }
+}
+
+fn build_single_handler(destination: &str) -> Result<BoxedLogHandler> {
+    match destination {
+        "loggly" => Ok(Box::new(loggly::from_env()?)),
+        "logzio" => Ok(Box::new(logzio::from_env()?)),
+        "firehose" => Ok(Box::new(firehose::from_env()?)),
+        "kinesis" => Ok(Box::new(kinesis::from_env()?)),
+        "s3" => Ok(Box::new(s3::from_env()?)),
+        "cloudwatch" => Ok(Box::new(cloudwatch::from_env()?)),
+        "sqs" => Ok(Box::new(sqs::from_env()?)),
+        #[cfg(feature = "kafka")]
+        "kafka" => Ok(Box::new(kafka::from_env()?)),
+        #[cfg(not(feature = "kafka"))]
+        "kafka" => Err(Error::msg("Woodchuck was built without the kafka feature")),
+        "datadog" => Ok(Box::new(datadog::from_env()?)),
+        "newrelic" => Ok(Box::new(newrelic::from_env()?)),
+        "sumologic" => Ok(Box::new(sumologic::from_env()?)),
+        "elasticsearch" | "opensearch" => Ok(Box::new(elasticsearch::from_env()?)),
+        "splunk" => Ok(Box::new(splunk::from_env()?)),
+        "loki" => Ok(Box::new(loki::from_env()?)),
+        "otlp" => Ok(Box::new(otlp::from_env()?)),
+        "syslog" => Ok(Box::new(syslog::from_env()?)),
+        "gelf" => Ok(Box::new(gelf::from_env()?)),
+        "fluentd" => Ok(Box::new(fluentd::from_env()?)),
+        "http" => Ok(Box::new(http::from_env()?)),
+        "custom" => Ok(Box::new(custom::Custom::new())),
+        _ => Err(Error::msg(format!("Unknown destination {}", destination))),
+    }
+}
+
+pub(crate) fn get_timeout(var: &str) -> Option<u64> {
+    match std::env::var(var) {
+        Ok(data) => match data.parse() {
+            Ok(0) => {
+                println!("{} set to Infinite", var);
+                None
+            }
+            Ok(t) => {
+                println!("{} set to {}ms", var, &t);
+                Some(t)
+            }
+            Err(_) => {
+                println!("{}: Cannot be parsed from {}", var, data);
+                Some(DEFAULT_TIMEOUT)
+            }
+        },
+        Err(_) => Some(DEFAULT_TIMEOUT),
+    }
+}
+
+pub(crate) fn get_required(var: &str) -> Result<String> {
+    std::env::var(var).map_err(|_| Error::msg(format!("{} Required", var)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aws_error, is_retryable, PermanentError, StatusError};
+    use anyhow::Error;
+    use reqwest::StatusCode;
+    use rusoto_core::credential::CredentialsError;
+    use rusoto_core::request::BufferedHttpResponse;
+    use rusoto_core::RusotoError;
+    use rusoto_logs::PutLogEventsError;
+
+    #[test]
+    fn classifies_statuses() {
+        let retryable = |status| is_retryable(&Error::new(StatusError(status)));
+
+        assert!(retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(retryable(StatusCode::REQUEST_TIMEOUT));
+        assert!(retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!retryable(StatusCode::BAD_REQUEST));
+        assert!(!retryable(StatusCode::UNAUTHORIZED));
+        assert!(!retryable(StatusCode::FORBIDDEN));
+        assert!(is_retryable(&Error::msg("connection reset")));
+    }
+
+    #[test]
+    fn classifies_aws_errors() {
+        let retryable = |error| {
+            is_retryable(&aws_error(error, |e| {
+                matches!(e, PutLogEventsError::ResourceNotFound(_))
+            }))
+        };
+        let unknown = |status, body: &'static str| {
+            RusotoError::Unknown(BufferedHttpResponse {
+                status,
+                body: body.into(),
+                headers: Default::default(),
+            })
+        };
+
+        assert!(!retryable(RusotoError::Service(
+            PutLogEventsError::ResourceNotFound("The specified log group does not exist.".into())
+        )));
+        assert!(retryable(RusotoError::Service(
+            PutLogEventsError::ServiceUnavailable("".into())
+        )));
+        assert!(!retryable(RusotoError::Validation(
+            "Invalid Log Group".into()
+        )));
+        assert!(!retryable(RusotoError::Credentials(CredentialsError::new(
+            "AccessDenied: Not authorized to perform sts:AssumeRole"
+        ))));
+        assert!(retryable(RusotoError::Credentials(CredentialsError::new(
+            "Timed out reading the instance metadata"
+        ))));
+        assert!(!retryable(unknown(
+            StatusCode::FORBIDDEN,
+            "<Error><Code>AccessDenied</Code></Error>"
+        )));
+        assert!(!retryable(unknown(
+            StatusCode::NOT_FOUND,
+            "<Error><Code>NoSuchBucket</Code></Error>"
+        )));
+        assert!(retryable(unknown(
+            StatusCode::BAD_REQUEST,
+            r#"{"__type":"ThrottlingException"}"#
+        )));
+        assert!(retryable(unknown(StatusCode::SERVICE_UNAVAILABLE, "")));
+    }
+
+    #[test]
+    fn classifies_permanent_errors() {
+        assert!(!is_retryable(&Error::new(PermanentError(
+            "Authentication failed".to_string()
+        ))));
     }
 }
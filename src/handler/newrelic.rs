@@ -0,0 +1,304 @@
+use crate::handler::{
+    get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Client;
+use rusoto_core::Region;
+use rusoto_sts::{GetCallerIdentityRequest, Sts, StsClient};
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const US_ENDPOINT: &str = "https://log-api.newrelic.com/log/v1";
+const EU_ENDPOINT: &str = "https://log-api.eu.newrelic.com/log/v1";
+const MAX_PAYLOAD_BYTES: usize = 1000000; //1MB compressed, keeping the uncompressed size under it is always safe.
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    ApiKey(String),
+    LicenseKey(String),
+}
+
+pub struct NewRelic {
+    url: String,
+    auth: Auth,
+    function_arn: Option<String>,
+    /// The `common` attributes, completed with the function ARN on first use.
+    common: Mutex<Option<Value>>,
+    client: Client,
+}
+
+pub fn from_env() -> Result<NewRelic> {
+    let mut builder = NewRelic::builder().with_timeout(get_timeout("NEW_RELIC_TIMEOUT"));
+    if let Ok(api_key) = std::env::var("NEW_RELIC_API_KEY") {
+        builder = builder.with_auth(Auth::ApiKey(api_key));
+    } else if let Ok(license_key) = std::env::var("NEW_RELIC_LICENSE_KEY") {
+        builder = builder.with_auth(Auth::LicenseKey(license_key));
+    }
+    if let Ok(region) = std::env::var("NEW_RELIC_REGION") {
+        builder = builder.with_url(match region.to_lowercase().as_str() {
+            "us" => US_ENDPOINT.to_string(),
+            "eu" => EU_ENDPOINT.to_string(),
+            _ => return Err(Error::msg(format!("Unable to parse {} as Region", region))),
+        });
+    }
+    if let Ok(url) = std::env::var("NEW_RELIC_LOG_ENDPOINT") {
+        builder = builder.with_url(url);
+    }
+    if let Ok(function_arn) = std::env::var("NEW_RELIC_FUNCTION_ARN") {
+        builder = builder.with_function_arn(function_arn);
+    }
+    builder.build()
+}
+
+/// The Lambda attributes available from the environment.
+fn lambda_attributes() -> Map<String, Value> {
+    let mut attributes = Map::new();
+    attributes.insert("plugin.type".to_string(), Value::from("woodchuck"));
+    for (name, var) in [
+        ("aws.lambda.function_name", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("aws.lambda.function_version", "AWS_LAMBDA_FUNCTION_VERSION"),
+        ("aws.region", "AWS_REGION"),
+    ]
+    .iter()
+    {
+        if let Ok(value) = std::env::var(var) {
+            attributes.insert(name.to_string(), Value::from(value));
+        }
+    }
+    if let Some(memory) = std::env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+        .ok()
+        .and_then(|memory| memory.parse::<u64>().ok())
+    {
+        attributes.insert("aws.lambda.memory_size".to_string(), Value::from(memory));
+    }
+    attributes
+}
+
+/// Builds the function ARN from the account the extension is running in, the extension is
+/// never told its ARN outside of invoke events. GetCallerIdentity needs no permissions.
+async fn lookup_function_arn() -> Result<String> {
+    let region = std::env::var("AWS_REGION")?;
+    let function_name = std::env::var("AWS_LAMBDA_FUNCTION_NAME")?;
+    let identity = StsClient::new(Region::default())
+        .get_caller_identity(GetCallerIdentityRequest {})
+        .await?;
+    let account = identity
+        .account
+        .ok_or_else(|| Error::msg("Account Required"))?;
+    Ok(format!(
+        "arn:aws:lambda:{}:{}:function:{}",
+        region, account, function_name
+    ))
+}
+
+impl NewRelic {
+    pub fn builder() -> NewRelicBuilder {
+        NewRelicBuilder::new()
+    }
+
+    async fn common(&self) -> Value {
+        let mut common = self.common.lock().await;
+        if let Some(common) = &*common {
+            return common.clone();
+        }
+
+        let mut attributes = lambda_attributes();
+        let function_arn = match &self.function_arn {
+            Some(function_arn) => Some(function_arn.clone()),
+            None => match lookup_function_arn().await {
+                Ok(function_arn) => Some(function_arn),
+                Err(e) => {
+                    log::error!("Unable to look up the function ARN: {}", e);
+                    None
+                }
+            },
+        };
+        if let Some(function_arn) = function_arn {
+            attributes.insert("aws.lambda.arn".to_string(), Value::from(function_arn));
+        }
+
+        let value = json!({ "attributes": attributes });
+        *common = Some(value.clone());
+        value
+    }
+
+    fn entry(log: &Log) -> Value {
+        let mut attributes = Map::new();
+        if let Some(level) = log.level() {
+            attributes.insert("level".to_string(), serde_json::to_value(level).unwrap());
+        }
+        if let Log::Unformatted(data) = log {
+            if let Some(guid) = &data.guid {
+                attributes.insert(
+                    "aws.lambda.request_id".to_string(),
+                    Value::from(guid.as_str()),
+                );
+            }
+        }
+        if let Some((trace_id, span_id)) = log.trace_context() {
+            attributes.insert("trace.id".to_string(), Value::from(trace_id));
+            attributes.insert("span.id".to_string(), Value::from(span_id));
+        }
+        json!({
+            "timestamp": log.timestamp().unwrap_or_else(Utc::now).timestamp_millis(),
+            "message": log.to_string(),
+            "attributes": attributes,
+        })
+    }
+
+    fn payload(&self, common: Value, logs: &[Log]) -> Result<Vec<u8>> {
+        let body = json!([{
+            "common": common,
+            "logs": logs.iter().map(NewRelic::entry).collect::<Vec<Value>>(),
+        }]);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serde_json::to_vec(&body)?)?;
+        Ok(encoder.finish()?)
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let payload = self.payload(self.common().await, logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let request = match &self.auth {
+            Auth::ApiKey(api_key) => self.client.post(&self.url).header("Api-Key", api_key),
+            Auth::LicenseKey(license_key) => self
+                .client
+                .post(&self.url)
+                .header("X-License-Key", license_key),
+        };
+        let res = request
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(payload)
+            .send()
+            .await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogHandler for NewRelic {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(MAX_PAYLOAD_BYTES);
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct NewRelicBuilder {
+    url: String,
+    auth: Option<Auth>,
+    function_arn: Option<String>,
+    timeout: Option<Duration>,
+}
+
+impl NewRelicBuilder {
+    pub fn new() -> Self {
+        NewRelicBuilder {
+            url: US_ENDPOINT.to_string(),
+            auth: None,
+            function_arn: None,
+            timeout: None,
+        }
+    }
+
+    /// Defaults to the US Log API endpoint.
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = url;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Skips looking up the function ARN.
+    pub fn with_function_arn(mut self, function_arn: String) -> Self {
+        self.function_arn = Some(function_arn);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<NewRelic> {
+        match self {
+            Self {
+                auth: Some(auth), ..
+            } => {
+                let client = match self.timeout {
+                    Some(duration) => Client::builder().timeout(duration).build()?,
+                    None => Client::builder().build()?,
+                };
+
+                Ok(NewRelic {
+                    url: self.url,
+                    auth,
+                    function_arn: self.function_arn,
+                    common: Mutex::new(None),
+                    client,
+                })
+            }
+            Self { auth: None, .. } => Err(Error::msg("Api Key or License Key Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NewRelic;
+    use crate::models::Log;
+
+    #[test]
+    fn attaches_trace_context() {
+        let log = Log::Formatted(serde_json::json!({
+            "level": "warn",
+            "dd": { "trace_id": "123", "span_id": "456" }
+        }));
+
+        let entry = NewRelic::entry(&log);
+
+        assert_eq!(entry["attributes"]["trace.id"], "123");
+        assert_eq!(entry["attributes"]["span.id"], "456");
+        assert_eq!(entry["attributes"]["level"], "WARN");
+    }
+}
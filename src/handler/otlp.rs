@@ -0,0 +1,602 @@
+use crate::handler::{
+    get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::{Log, LogLevel};
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::Utc;
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+const LOGS_PATH: &str = "/v1/logs";
+const GRPC_PATH: &str = "/opentelemetry.proto.collector.logs.v1.LogsService/Export";
+const SCOPE_NAME: &str = "woodchuck";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protocol {
+    HttpProtobuf,
+    HttpJson,
+    Grpc,
+}
+
+impl TryFrom<&str> for Protocol {
+    type Error = anyhow::Error;
+    fn try_from(protocol: &str) -> Result<Self> {
+        match protocol {
+            "http/protobuf" => Ok(Protocol::HttpProtobuf),
+            "http/json" => Ok(Protocol::HttpJson),
+            "grpc" => Ok(Protocol::Grpc),
+            _ => Err(Error::msg(format!(
+                "Unable to parse {} as Protocol",
+                protocol
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Otlp {
+    url: String,
+    protocol: Protocol,
+    resource: Vec<KeyValue>,
+    client: Client,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeLogs {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    severity_number: i32,
+    #[prost(string, tag = "3")]
+    severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    attributes: Vec<KeyValue>,
+    #[prost(bytes = "vec", tag = "9")]
+    trace_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "10")]
+    span_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AnyValue {
+    #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 5, 6")]
+    value: Option<any_value::Value>,
+}
+
+mod any_value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        String(String),
+        #[prost(bool, tag = "2")]
+        Bool(bool),
+        #[prost(int64, tag = "3")]
+        Int(i64),
+        #[prost(double, tag = "4")]
+        Double(f64),
+        #[prost(message, tag = "5")]
+        Array(super::ArrayValue),
+        #[prost(message, tag = "6")]
+        Kvlist(super::KeyValueList),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    values: Vec<KeyValue>,
+}
+
+impl From<&Value> for AnyValue {
+    fn from(value: &Value) -> Self {
+        let value = match value {
+            Value::Null => None,
+            Value::Bool(b) => Some(any_value::Value::Bool(*b)),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Some(any_value::Value::Int(i)),
+                None => Some(any_value::Value::Double(n.as_f64().unwrap_or_default())),
+            },
+            Value::String(s) => Some(any_value::Value::String(s.clone())),
+            Value::Array(values) => Some(any_value::Value::Array(ArrayValue {
+                values: values.iter().map(AnyValue::from).collect(),
+            })),
+            Value::Object(values) => Some(any_value::Value::Kvlist(KeyValueList {
+                values: values
+                    .iter()
+                    .map(|(k, v)| KeyValue::new(k, v.into()))
+                    .collect(),
+            })),
+        };
+        AnyValue { value }
+    }
+}
+
+impl KeyValue {
+    fn new(key: &str, value: AnyValue) -> Self {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(value),
+        }
+    }
+
+    fn string(key: &str, value: &str) -> Self {
+        KeyValue::new(key, (&Value::String(value.to_string())).into())
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "key": self.key,
+            "value": self.value.as_ref().map(AnyValue::to_json).unwrap_or_else(|| json!({})),
+        })
+    }
+}
+
+impl AnyValue {
+    fn to_json(&self) -> Value {
+        match &self.value {
+            None => json!({}),
+            Some(any_value::Value::String(s)) => json!({ "stringValue": s }),
+            Some(any_value::Value::Bool(b)) => json!({ "boolValue": b }),
+            Some(any_value::Value::Int(i)) => json!({ "intValue": i.to_string() }),
+            Some(any_value::Value::Double(d)) => json!({ "doubleValue": d }),
+            Some(any_value::Value::Array(a)) => json!({
+                "arrayValue": { "values": a.values.iter().map(AnyValue::to_json).collect::<Vec<Value>>() }
+            }),
+            Some(any_value::Value::Kvlist(kv)) => json!({
+                "kvlistValue": { "values": kv.values.iter().map(KeyValue::to_json).collect::<Vec<Value>>() }
+            }),
+        }
+    }
+}
+
+impl LogRecord {
+    fn to_json(&self) -> Value {
+        let mut record = json!({
+            "timeUnixNano": self.time_unix_nano.to_string(),
+            "observedTimeUnixNano": self.observed_time_unix_nano.to_string(),
+            "severityNumber": self.severity_number,
+            "severityText": self.severity_text,
+            "body": self.body.as_ref().map(AnyValue::to_json).unwrap_or_else(|| json!({})),
+            "attributes": self.attributes.iter().map(KeyValue::to_json).collect::<Vec<Value>>(),
+        });
+        if !self.trace_id.is_empty() {
+            record["traceId"] = json!(to_hex(&self.trace_id));
+            record["spanId"] = json!(to_hex(&self.span_id));
+        }
+        record
+    }
+}
+
+impl ExportLogsServiceRequest {
+    fn to_json(&self) -> Value {
+        json!({
+            "resourceLogs": self.resource_logs.iter().map(|resource_logs| json!({
+                "resource": {
+                    "attributes": resource_logs.resource.iter()
+                        .flat_map(|resource| resource.attributes.iter().map(KeyValue::to_json))
+                        .collect::<Vec<Value>>(),
+                },
+                "scopeLogs": resource_logs.scope_logs.iter().map(|scope_logs| json!({
+                    "scope": scope_logs.scope.as_ref().map(|scope| json!({
+                        "name": scope.name,
+                        "version": scope.version,
+                    })),
+                    "logRecords": scope_logs.log_records.iter().map(LogRecord::to_json).collect::<Vec<Value>>(),
+                })).collect::<Vec<Value>>(),
+            })).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+fn severity(level: &Option<LogLevel>) -> (i32, &'static str) {
+    match level {
+        Some(LogLevel::Trace) => (1, "TRACE"),
+        Some(LogLevel::Debug) => (5, "DEBUG"),
+        Some(LogLevel::Info) => (9, "INFO"),
+        Some(LogLevel::Warn) => (13, "WARN"),
+        Some(LogLevel::Error) => (17, "ERROR"),
+        Some(LogLevel::Critical) => (21, "FATAL"),
+        None => (0, ""),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a trace or span id, either hex encoded or a decimal 64 bit id as written by
+/// Datadog's tracers, into an id of `len` bytes.
+fn parse_id(id: &str, len: usize) -> Option<Vec<u8>> {
+    if id.len() == len * 2 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return (0..id.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&id[i..i + 2], 16).ok())
+            .collect();
+    }
+    let id: u64 = id.parse().ok()?;
+    let mut bytes = vec![0; len];
+    let be = id.to_be_bytes();
+    bytes[len - be.len()..].copy_from_slice(&be);
+    Some(bytes)
+}
+
+pub fn from_env() -> Result<Otlp> {
+    let mut builder = Otlp::builder().with_timeout(get_timeout("OTEL_EXPORTER_OTLP_TIMEOUT"));
+    let protocol = Protocol::try_from(
+        std::env::var("OTEL_EXPORTER_OTLP_PROTOCOL")
+            .unwrap_or_else(|_| "http/protobuf".to_string())
+            .as_str(),
+    )?;
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    builder = builder.with_url(
+        match (&protocol, std::env::var("OTEL_EXPORTER_OTLP_LOGS_ENDPOINT")) {
+            (_, Ok(url)) => url,
+            (Protocol::Grpc, Err(_)) => format!("{}{}", endpoint, GRPC_PATH),
+            (_, Err(_)) => format!("{}{}", endpoint, LOGS_PATH),
+        },
+    );
+    builder = builder.with_protocol(protocol);
+    if let Ok(headers) = std::env::var("OTEL_EXPORTER_OTLP_HEADERS") {
+        for (name, value) in headers.split(',').filter_map(|header| {
+            let mut parts = header.splitn(2, '=');
+            Some((parts.next()?.trim(), parts.next()?.trim()))
+        }) {
+            builder = builder.with_header(name, value)?;
+        }
+    }
+
+    let mut attributes: Vec<(String, String)> = vec![
+        ("cloud.provider".to_string(), "aws".to_string()),
+        ("cloud.platform".to_string(), "aws_lambda".to_string()),
+    ];
+    for (key, var) in [
+        ("service.name", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("faas.name", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("faas.version", "AWS_LAMBDA_FUNCTION_VERSION"),
+        ("faas.max_memory", "AWS_LAMBDA_FUNCTION_MEMORY_SIZE"),
+        ("cloud.region", "AWS_REGION"),
+    ]
+    .iter()
+    {
+        if let Ok(value) = std::env::var(var) {
+            attributes.push((key.to_string(), value));
+        }
+    }
+    if let Ok(service) = std::env::var("OTEL_SERVICE_NAME") {
+        attributes.push(("service.name".to_string(), service));
+    }
+    if let Ok(resource) = std::env::var("OTEL_RESOURCE_ATTRIBUTES") {
+        for attribute in resource.split(',') {
+            let mut parts = attribute.splitn(2, '=');
+            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                attributes.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+    }
+    for (key, value) in attributes.into_iter() {
+        builder = builder.with_resource_attribute(key, value);
+    }
+
+    builder.build()
+}
+
+impl Otlp {
+    pub fn builder() -> OtlpBuilder {
+        OtlpBuilder::new()
+    }
+
+    fn log_record(log: &Log, observed: u64) -> LogRecord {
+        let (body, level, attributes) = match log {
+            Log::Unformatted(data) => (
+                AnyValue::from(&data.data),
+                data.level.clone(),
+                data.guid
+                    .iter()
+                    .map(|guid| KeyValue::string("faas.invocation_id", guid))
+                    .collect(),
+            ),
+            Log::Formatted(data) => (AnyValue::from(data), log.level(), Vec::new()),
+        };
+        let (severity_number, severity_text) = severity(&level);
+        let (trace_id, span_id) = match log.trace_context() {
+            Some((trace_id, span_id)) => (
+                parse_id(&trace_id, 16).unwrap_or_default(),
+                parse_id(&span_id, 8).unwrap_or_default(),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+        LogRecord {
+            time_unix_nano: log.timestamp_nanos().unwrap_or(observed),
+            observed_time_unix_nano: observed,
+            severity_number,
+            severity_text: severity_text.to_string(),
+            body: Some(body),
+            attributes,
+            trace_id,
+            span_id,
+        }
+    }
+
+    fn request(&self, logs: &[Log]) -> ExportLogsServiceRequest {
+        let observed = Utc::now().timestamp_nanos() as u64;
+        ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes: self.resource.clone(),
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: SCOPE_NAME.to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    log_records: logs
+                        .iter()
+                        .map(|log| Self::log_record(log, observed))
+                        .collect(),
+                }],
+            }],
+        }
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let request = self.request(logs);
+        let (payload, content_type) = match self.protocol {
+            Protocol::HttpProtobuf => (request.encode_to_vec(), "application/x-protobuf"),
+            Protocol::HttpJson => (serde_json::to_vec(&request.to_json())?, "application/json"),
+            Protocol::Grpc => {
+                // gRPC length-prefixed message: uncompressed flag followed by a big endian length.
+                let message = request.encode_to_vec();
+                let mut payload = Vec::with_capacity(message.len() + 5);
+                payload.push(0);
+                payload.extend_from_slice(&(message.len() as u32).to_be_bytes());
+                payload.extend_from_slice(&message);
+                (payload, "application/grpc")
+            }
+        };
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, content_type);
+        let request = match self.protocol {
+            Protocol::Grpc => request.header("te", "trailers"),
+            _ => request,
+        };
+        let res = request.body(payload).send().await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        // Errors are reported in the trailers, which reqwest does not expose, but a failing
+        // collector usually answers with a trailers-only response carrying the status header.
+        if let Some(status) = res.headers().get("grpc-status") {
+            ensure!(
+                status == "0",
+                "Error Sending Logs, grpc-status: {:?}",
+                status
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogHandler for Otlp {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(3900000); //gRPC's default max message size is 4MB.
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct OtlpBuilder {
+    url: Option<String>,
+    protocol: Protocol,
+    headers: HeaderMap,
+    resource: Vec<KeyValue>,
+    timeout: Option<Duration>,
+}
+
+impl OtlpBuilder {
+    pub fn new() -> Self {
+        OtlpBuilder {
+            url: None,
+            protocol: Protocol::HttpProtobuf,
+            headers: HeaderMap::new(),
+            resource: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        self.headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+        Ok(self)
+    }
+
+    /// Adds a resource attribute, replacing any earlier attribute with the same key.
+    pub fn with_resource_attribute(mut self, key: String, value: String) -> Self {
+        self.resource.retain(|attribute| attribute.key != key);
+        self.resource.push(KeyValue::string(&key, &value));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Otlp> {
+        match self {
+            Self { url: Some(url), .. } => {
+                let builder = Client::builder().default_headers(self.headers);
+                let builder = match self.protocol {
+                    Protocol::Grpc => builder.http2_prior_knowledge(),
+                    _ => builder,
+                };
+                let client = match self.timeout {
+                    Some(duration) => builder.timeout(duration).build()?,
+                    None => builder.build()?,
+                };
+
+                Ok(Otlp {
+                    url,
+                    protocol: self.protocol,
+                    resource: self.resource,
+                    client,
+                })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_id, to_hex, Otlp};
+    use crate::models::Log;
+
+    #[test]
+    fn parses_datadog_and_hex_ids() {
+        assert_eq!(
+            to_hex(&parse_id("8698380355092788351", 16).unwrap()),
+            "000000000000000078b6dad8d28ab47f"
+        );
+        assert_eq!(
+            to_hex(&parse_id("5b8efff798038103d269b633813fc60c", 16).unwrap()),
+            "5b8efff798038103d269b633813fc60c"
+        );
+        assert!(parse_id("not an id", 8).is_none());
+    }
+
+    #[test]
+    fn maps_log_to_record() {
+        let log = Log::Formatted(serde_json::json!({
+            "level": "error",
+            "timestamp": "2020-11-18T23:52:30.128Z",
+            "dd": { "trace_id": "1", "span_id": "2" }
+        }));
+
+        let record = Otlp::log_record(&log, 0);
+
+        assert_eq!(record.severity_number, 17);
+        assert_eq!(record.severity_text, "ERROR");
+        assert_eq!(record.time_unix_nano, 1605743550128000000);
+        assert_eq!(to_hex(&record.span_id), "0000000000000002");
+    }
+
+    #[test]
+    fn uses_observed_time_for_out_of_range_timestamps() {
+        for timestamp in [
+            "0001-01-01T00:00:00Z",
+            "1969-12-31T23:59:59Z",
+            "2300-01-01T00:00:00Z",
+        ]
+        .iter()
+        {
+            let log = Log::Formatted(serde_json::json!({ "timestamp": timestamp }));
+
+            let record = Otlp::log_record(&log, 42);
+
+            assert_eq!(record.time_unix_nano, 42);
+        }
+    }
+}
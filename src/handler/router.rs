@@ -0,0 +1,174 @@
+use crate::handler::{
+    build_handler, merge_responses, BoxedLogHandler, FailedToSendLogsError, LogHandler,
+    LogHandlerResponse,
+};
+use crate::models::{Log, LogLevel};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A routing rule read from `WOODCHUCK_ROUTES`.
+///
+/// Every condition that is set must match, a rule without conditions matches every log.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Route {
+    pub destination: String,
+    #[serde(default)]
+    pub levels: Vec<LogLevel>,
+    pub field: Option<String>,
+    pub value: Option<Value>,
+}
+
+impl Route {
+    pub fn matches(&self, log: &Log) -> bool {
+        let level_matches = match self.levels.len() {
+            0 => true,
+            _ => match log.level() {
+                Some(level) => self.levels.contains(&level),
+                None => false,
+            },
+        };
+        let field_matches = match (&self.field, &self.value) {
+            (Some(field), Some(value)) => log.field(field) == Some(value),
+            (Some(field), None) => log.field(field).is_some(),
+            (None, _) => true,
+        };
+        level_matches && field_matches
+    }
+}
+
+pub fn routes_from_str(routes: &str) -> Result<Vec<Route>> {
+    Ok(serde_json::from_str(routes)?)
+}
+
+/// Sends each log to the destination of the first matching route, logs that match no
+/// route are sent to the default destination.
+pub struct Router {
+    routes: Vec<(Route, usize)>,
+    default: usize,
+    destinations: Vec<(String, BoxedLogHandler)>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>, default: String) -> Result<Self> {
+        let mut destinations: Vec<(String, BoxedLogHandler)> = Vec::new();
+        let mut index_of = |name: &str| -> Result<usize> {
+            match destinations.iter().position(|(d, _)| d == name) {
+                Some(index) => Ok(index),
+                None => {
+                    destinations.push((name.to_string(), build_handler(name)?));
+                    Ok(destinations.len() - 1)
+                }
+            }
+        };
+
+        let default = index_of(&default)?;
+        let routes = routes
+            .into_iter()
+            .map(|route| {
+                let index = index_of(&route.destination)?;
+                Ok((route, index))
+            })
+            .collect::<Result<Vec<(Route, usize)>>>()?;
+
+        Ok(Router {
+            routes,
+            default,
+            destinations,
+        })
+    }
+
+    fn route(&self, log: &Log) -> usize {
+        self.routes
+            .iter()
+            .find(|(route, _)| route.matches(log))
+            .map(|(_, index)| *index)
+            .unwrap_or(self.default)
+    }
+}
+
+#[async_trait]
+impl LogHandler for Router {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut batches: Vec<Vec<Log>> = vec![Vec::new(); self.destinations.len()];
+        for log in logs.into_iter() {
+            let index = self.route(&log);
+            batches[index].push(log);
+        }
+
+        let results = join_all(self.destinations.iter().zip(batches).map(
+            |((name, handler), batch)| async move {
+                match batch.len() {
+                    0 => Ok(()),
+                    _ => {
+                        log::debug!("Routing {} logs to {}", batch.len(), name);
+                        handler.handle_logs(batch).await
+                    }
+                }
+            },
+        ))
+        .await;
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+        for result in results.into_iter() {
+            if let Err(failed) = result {
+                failed_to_send_logs.extend(failed);
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+
+    async fn flush(&self) -> LogHandlerResponse {
+        merge_responses(
+            join_all(self.destinations.iter().map(|(_, handler)| handler.flush())).await,
+        )
+    }
+
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        merge_responses(
+            join_all(
+                self.destinations
+                    .iter()
+                    .map(|(_, handler)| handler.flush_expired()),
+            )
+            .await,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::routes_from_str;
+    use crate::models::{Log, LogLevel, StructuredLog};
+
+    #[test]
+    fn matches_level_and_field() {
+        let routes = routes_from_str(
+            r#"[
+                { "destination": "logzio", "levels": ["ERROR", "CRITICAL"] },
+                { "destination": "loggly", "field": "service", "value": "billing" }
+            ]"#,
+        )
+        .unwrap();
+
+        let error = Log::Unformatted(StructuredLog {
+            timestamp: None,
+            guid: None,
+            level: Some(LogLevel::Error),
+            data: serde_json::Value::String("Hello World".to_string()),
+            trace_id: None,
+            span_id: None,
+        });
+        let billing = Log::Formatted(serde_json::json!({ "service": "billing", "level": "info" }));
+        let other = Log::Formatted(serde_json::json!({ "service": "orders", "level": "info" }));
+
+        assert!(routes[0].matches(&error));
+        assert!(!routes[1].matches(&error));
+        assert!(!routes[0].matches(&billing));
+        assert!(routes[1].matches(&billing));
+        assert!(!routes.iter().any(|route| route.matches(&other)));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::handler::{
+    aws_error, get_required, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use rusoto_core::Region;
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DEFAULT_MAX_BATCH_BYTES: usize = 10000000;
+const DEFAULT_MAX_BATCH_AGE: u64 = 300000;
+const DEFAULT_KEY_TEMPLATE: &str =
+    "{{function_name}}/year=%Y/month=%m/day=%d/hour=%H/{{request_id}}-{{uuid}}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "ndjson.gz",
+            Compression::Zstd => "ndjson.zst",
+        }
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+}
+
+/// Logs held until the batch is large or old enough to be written as one object.
+struct Batch {
+    logs: Vec<Log>,
+    bytes: usize,
+    started: Option<Instant>,
+}
+
+pub struct S3Archive {
+    bucket: String,
+    key_template: String,
+    compression: Compression,
+    max_batch_bytes: usize,
+    max_batch_age: Duration,
+    write_immediately: bool,
+    function_name: String,
+    batch: Mutex<Batch>,
+    client: S3Client,
+}
+
+pub fn from_env() -> Result<S3Archive> {
+    let mut builder = S3Archive::builder().with_bucket(get_required("WOODCHUCK_S3_BUCKET")?);
+    if let Ok(key_template) = std::env::var("WOODCHUCK_S3_KEY_TEMPLATE") {
+        builder = builder.with_key_template(key_template);
+    }
+    if let Ok(compression) = std::env::var("WOODCHUCK_S3_COMPRESSION") {
+        builder = builder.with_compression(match compression.as_str() {
+            "gzip" => Compression::Gzip,
+            "zstd" => Compression::Zstd,
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unable to parse {} as Compression",
+                    compression
+                )))
+            }
+        });
+    }
+    if let Ok(max_batch_bytes) = std::env::var("WOODCHUCK_S3_MAX_BATCH_BYTES") {
+        builder = builder.with_max_batch_bytes(max_batch_bytes.parse()?);
+    }
+    if let Ok(max_batch_age) = std::env::var("WOODCHUCK_S3_MAX_BATCH_AGE") {
+        builder = builder.with_max_batch_age(max_batch_age.parse()?);
+    }
+    if let Ok(endpoint) = std::env::var("WOODCHUCK_S3_ENDPOINT") {
+        builder = builder.with_endpoint(endpoint);
+    }
+    builder.build()
+}
+
+impl S3Archive {
+    pub fn builder() -> S3ArchiveBuilder {
+        S3ArchiveBuilder::new()
+    }
+
+    /// Formats the date partitions in the template, then fills in the placeholders.
+    fn key(&self, logs: &[Log]) -> String {
+        let request_id = logs
+            .iter()
+            .find_map(|log| log.request_id())
+            .unwrap_or_else(|| "unknown".to_string());
+        format!(
+            "{}.{}",
+            Utc::now()
+                .format(&self.key_template)
+                .to_string()
+                .replace("{{function_name}}", &self.function_name)
+                .replace("{{request_id}}", &request_id)
+                .replace("{{uuid}}", &uuid::Uuid::new_v4().to_string()),
+            self.compression.extension()
+        )
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let payload: String = logs.iter().map(|x| x.to_string() + "\n").collect();
+        let body = self.compression.compress(payload.as_bytes())?;
+        let key = self.key(logs);
+
+        log::debug!(
+            "Writing {} logs to s3://{}/{}, payload length: {}",
+            &logs.len(),
+            &self.bucket,
+            &key,
+            &body.len()
+        );
+
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key,
+            body: Some(body.into()),
+            content_type: Some("application/x-ndjson".to_string()),
+            content_encoding: Some(self.compression.content_encoding().to_string()),
+            ..Default::default()
+        };
+
+        let _ = self
+            .client
+            .put_object(request)
+            .await
+            .map_err(|e| aws_error(e, |_| false))?;
+
+        Ok(())
+    }
+
+    /// Writes out the batch, handing the logs back on failure so they return to the queue.
+    async fn write_batch(&self, batch: &mut Batch) -> LogHandlerResponse {
+        let logs = batch.logs.split_off(0);
+        batch.bytes = 0;
+        batch.started = None;
+
+        match logs.len() {
+            0 => Ok(()),
+            _ => match self.send_logs(&logs).await {
+                Ok(_) => {
+                    log::debug!("Archived {} items.", logs.len());
+                    Ok(())
+                }
+                Err(e) => {
+                    log::debug!("Failed archiving {} items.", logs.len());
+                    log::error!("{}", e);
+                    let mut failed = FailedToSendLogsError::default();
+                    failed.add(&logs, &e);
+                    Err(failed)
+                }
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for S3Archive {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut batch = self.batch.lock().await;
+        batch.bytes += logs.iter().map(|x| x.to_string().len() + 1).sum::<usize>();
+        batch.logs.extend(logs);
+        let started = *batch.started.get_or_insert_with(Instant::now);
+
+        match self.write_immediately
+            || batch.bytes >= self.max_batch_bytes
+            || started.elapsed() >= self.max_batch_age
+        {
+            true => self.write_batch(&mut batch).await,
+            false => {
+                log::debug!("Holding {} items for archiving.", batch.logs.len());
+                Ok(())
+            }
+        }
+    }
+
+    async fn flush(&self) -> LogHandlerResponse {
+        let mut batch = self.batch.lock().await;
+        self.write_batch(&mut batch).await
+    }
+
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        let mut batch = self.batch.lock().await;
+        match batch.started {
+            Some(started) if started.elapsed() >= self.max_batch_age => {
+                self.write_batch(&mut batch).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct S3ArchiveBuilder {
+    bucket: Option<String>,
+    key_template: String,
+    compression: Compression,
+    max_batch_bytes: usize,
+    max_batch_age: u64,
+    write_immediately: bool,
+    endpoint: Option<String>,
+}
+
+impl S3ArchiveBuilder {
+    pub fn new() -> Self {
+        S3ArchiveBuilder {
+            bucket: None,
+            key_template: DEFAULT_KEY_TEMPLATE.to_string(),
+            compression: Compression::Gzip,
+            max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+            max_batch_age: DEFAULT_MAX_BATCH_AGE,
+            write_immediately: false,
+            endpoint: None,
+        }
+    }
+
+    pub fn with_bucket(mut self, bucket: String) -> Self {
+        self.bucket = Some(bucket);
+        self
+    }
+
+    /// The object key without extension, `strftime` specifiers are replaced with the upload
+    /// time and `{{function_name}}`, `{{request_id}}` and `{{uuid}}` are substituted.
+    pub fn with_key_template(mut self, key_template: String) -> Self {
+        self.key_template = key_template;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.max_batch_bytes = max_batch_bytes;
+        self
+    }
+
+    /// How long in milliseconds logs may be held before they are written.
+    pub fn with_max_batch_age(mut self, max_batch_age: u64) -> Self {
+        self.max_batch_age = max_batch_age;
+        self
+    }
+
+    /// Writes the logs of every call as their own object instead of batching them.
+    pub fn with_write_immediately(mut self) -> Self {
+        self.write_immediately = true;
+        self
+    }
+
+    /// Sends requests to an S3 compatible endpoint instead of AWS.
+    pub fn with_endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn build(self) -> Result<S3Archive> {
+        match self {
+            Self {
+                bucket: Some(bucket),
+                ..
+            } => {
+                ensure!(
+                    !StrftimeItems::new(&self.key_template).any(|item| item == Item::Error),
+                    "Invalid Key Template {}",
+                    self.key_template
+                );
+
+                let region = match self.endpoint {
+                    Some(endpoint) => Region::Custom {
+                        name: std::env::var("AWS_REGION")
+                            .unwrap_or_else(|_| "us-east-1".to_string()),
+                        endpoint,
+                    },
+                    None => Region::default(),
+                };
+
+                Ok(S3Archive {
+                    bucket,
+                    key_template: self.key_template,
+                    compression: self.compression,
+                    max_batch_bytes: self.max_batch_bytes,
+                    max_batch_age: Duration::from_millis(self.max_batch_age),
+                    write_immediately: self.write_immediately,
+                    function_name: std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                        .unwrap_or_else(|_| "woodchuck".to_string()),
+                    batch: Mutex::new(Batch {
+                        logs: Vec::new(),
+                        bytes: 0,
+                        started: None,
+                    }),
+                    client: S3Client::new(region),
+                })
+            }
+            Self { bucket: None, .. } => Err(Error::msg("Bucket Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, S3Archive};
+    use crate::handler::LogHandler;
+    use crate::models::{Log, StructuredLog};
+    use std::io::Read;
+
+    #[test]
+    fn renders_key() {
+        let archive = S3Archive::builder()
+            .with_bucket("logs".to_string())
+            .with_key_template("archive/%Y/{{request_id}}".to_string())
+            .with_endpoint("http://localhost:9000".to_string())
+            .build()
+            .unwrap();
+        let logs = vec![Log::Unformatted(StructuredLog {
+            timestamp: None,
+            guid: Some("6e48723a-1596-4313-a9af-e4da9214d637".to_string()),
+            level: None,
+            data: serde_json::Value::String("Hello World".to_string()),
+            trace_id: None,
+            span_id: None,
+        })];
+
+        let key = archive.key(&logs);
+
+        assert!(key.starts_with("archive/20"));
+        assert!(key.ends_with("/6e48723a-1596-4313-a9af-e4da9214d637.ndjson.gz"));
+
+        let logs = vec![Log::Formatted(serde_json::json!({
+            "requestId": "79b4f56e-95b1-4643-9700-2807f4e68189",
+            "message": "Hello World"
+        }))];
+
+        let key = archive.key(&logs);
+
+        assert!(key.ends_with("/79b4f56e-95b1-4643-9700-2807f4e68189.ndjson.gz"));
+    }
+
+    #[test]
+    fn compresses_gzip() {
+        let compressed = Compression::Gzip.compress(b"Hello World\n").unwrap();
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, "Hello World\n");
+    }
+
+    #[tokio::test]
+    async fn holds_batch_until_expired() {
+        let archive = S3Archive::builder()
+            .with_bucket("logs".to_string())
+            .with_endpoint("http://localhost:9000".to_string())
+            .with_max_batch_age(60000)
+            .build()
+            .unwrap();
+        let logs = vec![Log::Formatted(
+            serde_json::json!({ "message": "Hello World" }),
+        )];
+
+        assert!(archive.handle_logs(logs).await.is_ok());
+        assert!(archive.flush_expired().await.is_ok());
+
+        assert_eq!(archive.batch.lock().await.logs.len(), 1);
+    }
+}
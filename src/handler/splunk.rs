@@ -0,0 +1,405 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CHANNEL_HEADER: &str = "X-Splunk-Request-Channel";
+const ACK_POLL_INTERVAL: u64 = 250;
+const DEFAULT_ACK_TIMEOUT: u64 = 5000;
+
+#[derive(Debug, Clone)]
+pub struct Splunk {
+    url: String,
+    ack_url: String,
+    token: String,
+    metadata: SplunkMetadata,
+    channel: Option<String>,
+    /// Set once HEC answers without an ack id, when acknowledgement is disabled for the token.
+    acks_disabled: Arc<AtomicBool>,
+    ack_timeout: Duration,
+    client: Client,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct SplunkMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sourcetype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SplunkEvent<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<f64>,
+    #[serde(flatten)]
+    metadata: &'a SplunkMetadata,
+    event: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventResponse {
+    ack_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AckResponse {
+    acks: HashMap<String, bool>,
+}
+
+impl AckResponse {
+    fn is_acknowledged(&self, ack_id: u64) -> bool {
+        self.acks.get(&ack_id.to_string()) == Some(&true)
+    }
+}
+
+pub fn from_env() -> Result<Splunk> {
+    let mut builder = Splunk::builder()
+        .with_url(get_required("SPLUNK_HEC_URL")?)
+        .with_token(get_required("SPLUNK_HEC_TOKEN")?)
+        .with_timeout(get_timeout("SPLUNK_TIMEOUT"));
+    if let Ok(index) = std::env::var("SPLUNK_INDEX") {
+        builder = builder.with_index(index);
+    }
+    if let Ok(source) =
+        std::env::var("SPLUNK_SOURCE").or_else(|_| std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+    {
+        builder = builder.with_source(source);
+    }
+    if let Ok(sourcetype) = std::env::var("SPLUNK_SOURCETYPE") {
+        builder = builder.with_sourcetype(sourcetype);
+    }
+    if let Ok(host) = std::env::var("SPLUNK_HOST") {
+        builder = builder.with_host(host);
+    }
+    if let Ok(channel) = std::env::var("SPLUNK_HEC_ACK_CHANNEL") {
+        builder = builder.with_ack_channel(channel);
+    }
+    if let Ok(Ok(ack_timeout)) = std::env::var("SPLUNK_HEC_ACK_TIMEOUT").map(|t| t.parse()) {
+        builder = builder.with_ack_timeout(ack_timeout);
+    }
+    builder.build()
+}
+
+impl Splunk {
+    pub fn builder() -> SplunkBuilder {
+        SplunkBuilder::new()
+    }
+
+    fn payload(&self, logs: &[Log]) -> Result<String> {
+        let mut payload = String::new();
+        for log in logs.iter() {
+            let event = SplunkEvent {
+                time: log
+                    .timestamp()
+                    .map(|timestamp| timestamp.timestamp_millis() as f64 / 1000.0),
+                metadata: &self.metadata,
+                event: match log {
+                    Log::Unformatted(data) => serde_json::to_value(data)?,
+                    Log::Formatted(data) => data.clone(),
+                },
+            };
+            payload.push_str(&serde_json::to_string(&event)?);
+        }
+        Ok(payload)
+    }
+
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let payload = self.payload(logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(AUTHORIZATION, format!("Splunk {}", self.token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload);
+        let channel = self
+            .channel
+            .as_ref()
+            .filter(|_| !self.acks_disabled.load(Ordering::SeqCst));
+        let request = match channel {
+            Some(channel) => request.header(CHANNEL_HEADER, channel),
+            None => request,
+        };
+        let res = request.send().await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        // Events are only delivered once indexed, a batch that is never acknowledged is sent
+        // again. Acknowledgement is only skipped when HEC answers without an ack id.
+        if let Some(channel) = channel {
+            match res.json::<EventResponse>().await?.ack_id {
+                Some(ack_id) => self.wait_for_ack(channel, ack_id).await?,
+                None => {
+                    log::error!(
+                        "Indexer acknowledgement is not enabled, no longer waiting for acks"
+                    );
+                    self.acks_disabled.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Polls the acknowledgement endpoint until the batch has been indexed.
+    async fn wait_for_ack(&self, channel: &str, ack_id: u64) -> Result<()> {
+        let started = Instant::now();
+        loop {
+            let res = self
+                .client
+                .post(&self.ack_url)
+                .query(&[("channel", channel)])
+                .header(AUTHORIZATION, format!("Splunk {}", self.token))
+                .header(CHANNEL_HEADER, channel)
+                .json(&serde_json::json!({ "acks": [ack_id] }))
+                .send()
+                .await?;
+
+            ensure!(res.status().is_success(), "Error Checking Acknowledgement");
+
+            let response: AckResponse = res.json().await?;
+            if response.is_acknowledged(ack_id) {
+                log::debug!("Acknowledged {}", ack_id);
+                return Ok(());
+            }
+
+            ensure!(
+                started.elapsed() < self.ack_timeout,
+                "Timed out waiting for acknowledgement {}",
+                ack_id
+            );
+            tokio::time::sleep(Duration::from_millis(ACK_POLL_INTERVAL)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for Splunk {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(900000); //HEC's default max_content_length is 1MB.
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct SplunkBuilder {
+    url: Option<String>,
+    token: Option<String>,
+    metadata: SplunkMetadata,
+    channel: Option<String>,
+    ack_timeout: Duration,
+    timeout: Option<Duration>,
+}
+
+impl SplunkBuilder {
+    pub fn new() -> Self {
+        SplunkBuilder {
+            url: None,
+            token: None,
+            metadata: SplunkMetadata::default(),
+            channel: None,
+            ack_timeout: Duration::from_millis(DEFAULT_ACK_TIMEOUT),
+            timeout: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_index(mut self, index: String) -> Self {
+        self.metadata.index = Some(index);
+        self
+    }
+
+    pub fn with_source(mut self, source: String) -> Self {
+        self.metadata.source = Some(source);
+        self
+    }
+
+    pub fn with_sourcetype(mut self, sourcetype: String) -> Self {
+        self.metadata.sourcetype = Some(sourcetype);
+        self
+    }
+
+    pub fn with_host(mut self, host: String) -> Self {
+        self.metadata.host = Some(host);
+        self
+    }
+
+    /// Waits for HEC indexer acknowledgement on the given channel before a batch is
+    /// considered delivered.
+    pub fn with_ack_channel(mut self, channel: String) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub fn with_ack_timeout(mut self, ack_timeout: u64) -> Self {
+        self.ack_timeout = Duration::from_millis(ack_timeout);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Splunk> {
+        match self {
+            Self {
+                url: Some(url),
+                token: Some(token),
+                ..
+            } => {
+                let client = match self.timeout {
+                    Some(duration) => Client::builder().timeout(duration).build()?,
+                    None => Client::builder().build()?,
+                };
+
+                let url = url.trim_end_matches('/');
+                Ok(Splunk {
+                    url: format!("{}/services/collector/event", url),
+                    ack_url: format!("{}/services/collector/ack", url),
+                    token,
+                    metadata: self.metadata,
+                    channel: self.channel,
+                    acks_disabled: Arc::new(AtomicBool::new(false)),
+                    ack_timeout: self.ack_timeout,
+                    client,
+                })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+            Self { token: None, .. } => Err(Error::msg("Token Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckResponse, EventResponse, Splunk};
+    use crate::handler::LogHandler;
+    use crate::models::Log;
+    use warp::Filter;
+
+    #[test]
+    fn builds_payload() {
+        let splunk = Splunk::builder()
+            .with_url("https://splunk:8088/".to_string())
+            .with_token("token".to_string())
+            .with_index("main".to_string())
+            .with_source("my-function".to_string())
+            .build()
+            .unwrap();
+        let logs = vec![
+            Log::Formatted(serde_json::json!({
+                "timestamp": "2020-11-18T23:52:30.128Z",
+                "message": "Hello World"
+            })),
+            Log::Formatted(serde_json::json!("Goodbye")),
+        ];
+
+        let payload = splunk.payload(&logs).unwrap();
+
+        let events: Vec<serde_json::Value> = serde_json::Deserializer::from_str(&payload)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(splunk.url, "https://splunk:8088/services/collector/event");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["time"], 1605743550.128);
+        assert_eq!(events[0]["index"], "main");
+        assert_eq!(events[0]["source"], "my-function");
+        assert_eq!(events[0]["event"]["message"], "Hello World");
+        assert!(events[1].get("time").is_none());
+        assert_eq!(events[1]["event"], "Goodbye");
+    }
+
+    #[test]
+    fn parses_ack_responses() {
+        let event: EventResponse =
+            serde_json::from_str(r#"{"text":"Success","code":0,"ackId":7}"#).unwrap();
+        let without_ack: EventResponse =
+            serde_json::from_str(r#"{"text":"Success","code":0}"#).unwrap();
+        let acks: AckResponse = serde_json::from_str(r#"{"acks":{"7":true,"8":false}}"#).unwrap();
+
+        assert_eq!(event.ack_id, Some(7));
+        assert_eq!(without_ack.ack_id, None);
+        assert!(acks.is_acknowledged(7));
+        assert!(!acks.is_acknowledged(8));
+        assert!(!acks.is_acknowledged(9));
+    }
+
+    #[tokio::test]
+    async fn retries_unacknowledged_batches() {
+        let event = warp::path!("services" / "collector" / "event")
+            .map(|| warp::reply::json(&serde_json::json!({ "code": 0, "ackId": 1 })));
+        let ack = warp::path!("services" / "collector" / "ack")
+            .map(|| warp::reply::json(&serde_json::json!({ "acks": { "1": false } })));
+        let (address, server) = warp::serve(event.or(ack)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let splunk = Splunk::builder()
+            .with_url(format!("http://{}", address))
+            .with_token("token".to_string())
+            .with_ack_channel("0aecb4f3-4ed6-4b35-8b4e-c2d8b6c0ae57".to_string())
+            .with_ack_timeout(0)
+            .build()
+            .unwrap();
+
+        let failed = splunk
+            .handle_logs(vec![Log::Formatted(serde_json::json!("Hello World"))])
+            .await;
+
+        match failed {
+            Ok(_) => panic!("Expected unacknowledged logs"),
+            Err(e) => {
+                assert_eq!(e.logs.len(), 1);
+                assert!(e.rejected.is_empty());
+            }
+        }
+    }
+}
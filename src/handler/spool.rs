@@ -0,0 +1,569 @@
+use crate::extension::retry::RetryPolicy;
+use crate::handler::{BoxedLogHandler, FailedToSendLogsError, LogHandler, LogHandlerResponse};
+use crate::models::Log;
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const DEFAULT_DIR: &str = "/tmp/woodchuck-spool";
+const DEFAULT_MAX_BYTES: u64 = 50000000; //Lambda provides 512MB of /tmp by default, leave most of it to the function.
+const SEGMENT_BYTES: u64 = 1000000;
+const HEADER_BYTES: usize = 8;
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// Append-only segment files of logs, each record is its length and CRC32 followed by the logs
+/// as JSON. The oldest segments are dropped to stay under `max_bytes`.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Failed replays of each segment, segments that have been replayed are not appended to.
+    attempts: Mutex<HashMap<u64, u32>>,
+}
+
+/// The logs of a segment being replayed, it stays on disk until they are settled.
+struct Segment {
+    sequence: u64,
+    /// The bytes read, anything after them was appended during the replay.
+    length: usize,
+    logs: Vec<Log>,
+    attempts: u32,
+}
+
+pub fn from_env() -> Result<Spool> {
+    let dir = std::env::var("WOODCHUCK_SPOOL_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string());
+    let max_bytes = match std::env::var("WOODCHUCK_SPOOL_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes.parse()?,
+        Err(_) => DEFAULT_MAX_BYTES,
+    };
+    Spool::new(PathBuf::from(dir), max_bytes)
+}
+
+fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_BYTES + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Reads records until the end of the segment, stopping at a truncated or corrupt record since
+/// nothing after it can be trusted.
+fn decode(mut data: &[u8]) -> Vec<Log> {
+    let mut logs = Vec::new();
+    while data.len() >= HEADER_BYTES {
+        let length = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let payload = match data.get(HEADER_BYTES..HEADER_BYTES + length) {
+            Some(payload) if crc32fast::hash(payload) == checksum => payload,
+            _ => {
+                log::error!("Discarding corrupt spool record");
+                return logs;
+            }
+        };
+        match serde_json::from_slice::<Vec<Log>>(payload) {
+            Ok(records) => logs.extend(records),
+            Err(e) => log::error!("Discarding unreadable spool record: {}", e),
+        }
+        data = &data[HEADER_BYTES + length..];
+    }
+    if !data.is_empty() {
+        log::error!("Discarding truncated spool record");
+    }
+    logs
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Result<Spool> {
+        fs::create_dir_all(&dir)?;
+        Ok(Spool {
+            dir,
+            max_bytes,
+            // Segments are dropped whole, so keep them small next to the cap.
+            segment_bytes: SEGMENT_BYTES.min(max_bytes / 4),
+            attempts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The segments oldest first, with their sequence numbers and sizes.
+    fn segments(&self) -> Result<Vec<(u64, PathBuf, u64)>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(sequence) = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                let size = fs::metadata(&path)?.len();
+                segments.push((sequence, path, size));
+            }
+        }
+        segments.sort_by_key(|(sequence, _, _)| *sequence);
+        Ok(segments)
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}.{}", sequence, SEGMENT_EXTENSION))
+    }
+
+    pub fn append(&self, logs: &[Log]) -> Result<()> {
+        let record = encode(&serde_json::to_vec(logs)?);
+        let length = record.len() as u64;
+        ensure!(
+            length <= self.max_bytes,
+            "Record of {} bytes does not fit in the spool",
+            length
+        );
+
+        let mut attempts = self.attempts.lock().unwrap();
+        let mut segments = self.segments()?;
+        let mut total: u64 = segments.iter().map(|(_, _, size)| size).sum();
+        while total + length > self.max_bytes && !segments.is_empty() {
+            let (sequence, path, size) = segments.remove(0);
+            log::error!("Spool full, dropping {}", path.display());
+            fs::remove_file(&path)?;
+            attempts.remove(&sequence);
+            total -= size;
+        }
+
+        let path = match segments.last() {
+            Some((sequence, path, size))
+                if size + length <= self.segment_bytes && !attempts.contains_key(sequence) =>
+            {
+                path.clone()
+            }
+            Some((sequence, _, _)) => self.segment_path(sequence + 1),
+            None => self.segment_path(0),
+        };
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&record)?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let _attempts = self.attempts.lock().unwrap();
+        Ok(self.segments()?.is_empty())
+    }
+
+    /// Reads back the oldest segment, leaving it on disk until it is settled.
+    fn oldest(&self) -> Result<Option<Segment>> {
+        let attempts = self.attempts.lock().unwrap();
+        let (sequence, path, _) = match self.segments()?.into_iter().next() {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let data = fs::read(&path)?;
+        Ok(Some(Segment {
+            sequence,
+            length: data.len(),
+            logs: decode(&data),
+            attempts: attempts.get(&sequence).copied().unwrap_or(0),
+        }))
+    }
+
+    /// Replaces a replayed segment with the logs that remain to be sent, removing it once there
+    /// are none. Logs appended to it during the replay are moved to the next segment.
+    fn settle(&self, segment: &Segment, remaining: &[Log]) -> Result<()> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let path = self.segment_path(segment.sequence);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            // Dropped while the spool was full.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                attempts.remove(&segment.sequence);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Only the newest segment is appended to, so the next one is free.
+        if let Some(appended) = data.get(segment.length..).filter(|x| !x.is_empty()) {
+            fs::write(self.segment_path(segment.sequence + 1), appended)?;
+        }
+        if remaining.is_empty() {
+            fs::remove_file(&path)?;
+            attempts.remove(&segment.sequence);
+        } else {
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, encode(&serde_json::to_vec(remaining)?))?;
+            fs::rename(&temporary, &path)?;
+            attempts.insert(segment.sequence, segment.attempts + 1);
+        }
+        Ok(())
+    }
+}
+
+/// Writes logs the destination failed to take to the spool instead of returning them to the
+/// in-memory queue, and replays them oldest segment first, backing off with the retry policy.
+/// A segment is only removed once its logs are delivered, and logs that still fail after
+/// `max_attempts` replays are given up on and returned as rejected. Whatever is left from a
+/// previous execution environment in the same sandbox is sent ahead of the first batch.
+pub struct Spooled {
+    destination: BoxedLogHandler,
+    spool: Spool,
+    policy: RetryPolicy,
+    /// Set once flushing for shutdown, when the spool will not outlive the sandbox.
+    draining: AtomicBool,
+    /// When to replay the spool after a failed send, `None` to replay with the next batch.
+    retry_at: Mutex<Option<Instant>>,
+    replaying: tokio::sync::Mutex<()>,
+}
+
+impl Spooled {
+    pub fn new(destination: BoxedLogHandler, spool: Spool, policy: RetryPolicy) -> Self {
+        Spooled {
+            destination,
+            spool,
+            policy,
+            draining: AtomicBool::new(false),
+            retry_at: Mutex::new(None),
+            replaying: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+            || self
+                .retry_at
+                .lock()
+                .unwrap()
+                .is_none_or(|retry_at| retry_at <= Instant::now())
+    }
+
+    /// Sends the spooled segments until one fails, returning the logs given up on and whether
+    /// the spool was emptied. While draining, failing logs are returned to the queue instead.
+    async fn replay(&self) -> (FailedToSendLogsError, bool) {
+        let _replaying = self.replaying.lock().await;
+        let draining = self.draining.load(Ordering::SeqCst);
+        let mut given_up = FailedToSendLogsError::default();
+        loop {
+            let segment = match self.spool.oldest() {
+                Ok(Some(segment)) => segment,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Unable to read spool: {}", e);
+                    return (given_up, false);
+                }
+            };
+
+            log::debug!("Replaying {} spooled logs", segment.logs.len());
+            let mut remaining = match self.destination.handle_logs(segment.logs.clone()).await {
+                Ok(_) => Vec::new(),
+                Err(failed) => {
+                    given_up.rejected.extend(failed.rejected);
+                    if failed.reason.is_some() {
+                        given_up.reason = failed.reason;
+                    }
+                    failed.logs
+                }
+            };
+            let attempts = segment.attempts + 1;
+            if !remaining.is_empty() && draining {
+                given_up.logs.append(&mut remaining);
+            } else if !remaining.is_empty() && attempts >= self.policy.max_attempts {
+                println!(
+                    "giving up on {} spooled logs after {} attempts",
+                    remaining.len(),
+                    attempts
+                );
+                given_up.reason = Some(format!("Gave up after {} attempts", attempts));
+                given_up.rejected.append(&mut remaining);
+            }
+
+            if let Err(e) = self.spool.settle(&segment, &remaining) {
+                log::error!("Unable to settle spool: {}", e);
+                return (given_up, false);
+            }
+            if !remaining.is_empty() {
+                *self.retry_at.lock().unwrap() =
+                    Some(Instant::now() + self.policy.delay(attempts - 1));
+                return (given_up, false);
+            }
+        }
+        *self.retry_at.lock().unwrap() = None;
+        (given_up, true)
+    }
+}
+
+#[async_trait]
+impl LogHandler for Spooled {
+    /// Logs are only sent once the spool is empty, otherwise they are spooled behind it.
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let (mut failed_to_send_logs, caught_up) = match self.due() {
+            true => self.replay().await,
+            false => match self.spool.is_empty() {
+                Ok(empty) => (FailedToSendLogsError::default(), empty),
+                Err(e) => {
+                    log::error!("Unable to read spool: {}", e);
+                    (FailedToSendLogsError::default(), true)
+                }
+            },
+        };
+
+        let logs = match caught_up {
+            true => match self.destination.handle_logs(logs).await {
+                Ok(_) => Vec::new(),
+                Err(mut failed) => {
+                    let logs = std::mem::take(&mut failed.logs);
+                    failed_to_send_logs.extend(failed);
+                    logs
+                }
+            },
+            false => logs,
+        };
+        if logs.is_empty() {
+            return failed_to_send_logs.into_response();
+        }
+
+        match self.draining.load(Ordering::SeqCst) {
+            true => failed_to_send_logs.logs.extend(logs),
+            false => match self.spool.append(&logs) {
+                Ok(_) => {
+                    log::debug!("Spooled {} logs", logs.len());
+                    self.retry_at
+                        .lock()
+                        .unwrap()
+                        .get_or_insert_with(|| Instant::now() + self.policy.delay(0));
+                }
+                Err(e) => {
+                    log::error!("Unable to spool {} logs: {}", logs.len(), e);
+                    failed_to_send_logs.logs.extend(logs);
+                }
+            },
+        }
+        failed_to_send_logs.into_response()
+    }
+
+    async fn flush(&self) -> LogHandlerResponse {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let (mut failed_to_send_logs, _) = self.replay().await;
+        if let Err(failed) = self.destination.flush().await {
+            failed_to_send_logs.extend(failed);
+        }
+        failed_to_send_logs.into_response()
+    }
+
+    /// Replays the spool once its backoff has passed.
+    async fn flush_expired(&self) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+        if self.due() {
+            failed_to_send_logs.extend(self.replay().await.0);
+        }
+        if let Err(failed) = self.destination.flush_expired().await {
+            failed_to_send_logs.extend(failed);
+        }
+        failed_to_send_logs.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Spool, Spooled};
+    use crate::extension::retry::RetryPolicy;
+    use crate::handler::{LogHandler, LogHandlerResponse};
+    use crate::models::Log;
+    use async_trait::async_trait;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+
+    fn log(message: &str) -> Log {
+        Log::Formatted(serde_json::json!({ "message": message }))
+    }
+
+    fn spool(max_bytes: u64) -> Spool {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        Spool::new(dir, max_bytes).unwrap()
+    }
+
+    /// Reads back every spooled log oldest first, removing the segments.
+    fn take(spool: &Spool) -> Vec<Log> {
+        let mut logs = Vec::new();
+        while let Some(segment) = spool.oldest().unwrap() {
+            spool.settle(&segment, &[]).unwrap();
+            logs.extend(segment.logs);
+        }
+        logs
+    }
+
+    struct Recording {
+        failing: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogHandler for Recording {
+        async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(logs.into()),
+                false => {
+                    self.received
+                        .lock()
+                        .await
+                        .extend(logs.iter().map(|x| x.to_string()));
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn spooled(max_attempts: u32) -> (Spooled, Arc<AtomicBool>, Arc<Mutex<Vec<String>>>) {
+        let failing = Arc::new(AtomicBool::new(true));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let destination = Box::new(Recording {
+            failing: failing.clone(),
+            received: received.clone(),
+        });
+        let policy = RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: false,
+            budget: None,
+        };
+        (
+            Spooled::new(destination, spool(1000000), policy),
+            failing,
+            received,
+        )
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let spool = spool(1000000);
+        spool.append(&[log("first"), log("second")]).unwrap();
+        spool.append(&[log("third")]).unwrap();
+
+        let logs = take(&spool);
+
+        let messages: Vec<String> = logs.iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                log("first").to_string(),
+                log("second").to_string(),
+                log("third").to_string()
+            ]
+        );
+        assert!(take(&spool).is_empty());
+    }
+
+    #[test]
+    fn stops_at_corrupt_record() {
+        let spool = spool(1000000);
+        spool.append(&[log("intact")]).unwrap();
+        let (_, path, _) = spool.segments().unwrap().pop().unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(&[20, 0, 0, 0, 1, 2, 3, 4, b'['])
+            .unwrap();
+
+        let logs = take(&spool);
+
+        assert_eq!(logs.len(), 1);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let record = super::encode(&serde_json::to_vec(&[log("message 0")]).unwrap());
+        let spool = spool(record.len() as u64 * 2);
+        for index in 0..3 {
+            spool.append(&[log(&format!("message {}", index))]).unwrap();
+        }
+
+        let logs = take(&spool);
+
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].to_string(), log("message 1").to_string());
+    }
+
+    #[test]
+    fn keeps_logs_appended_while_replaying() {
+        let spool = spool(1000000);
+        spool.append(&[log("first"), log("second")]).unwrap();
+
+        let segment = spool.oldest().unwrap().unwrap();
+        spool.append(&[log("third")]).unwrap();
+        spool.settle(&segment, &[log("second")]).unwrap();
+
+        let messages: Vec<String> = take(&spool).iter().map(|x| x.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![log("second").to_string(), log("third").to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_segments_until_delivered() {
+        let (spooled, failing, received) = spooled(5);
+
+        let sent = spooled.handle_logs(vec![log("first")]).await;
+        let retried = spooled.flush_expired().await;
+        let queued = spooled.handle_logs(vec![log("second")]).await;
+        let held = spooled.spool.is_empty().unwrap();
+        failing.store(false, Ordering::SeqCst);
+        let replayed = spooled.flush_expired().await;
+
+        assert!(sent.is_ok() && retried.is_ok() && queued.is_ok() && replayed.is_ok());
+        assert!(!held);
+        assert!(spooled.spool.is_empty().unwrap());
+        assert_eq!(
+            *received.lock().await,
+            vec![log("first").to_string(), log("second").to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_logs_after_max_attempts() {
+        let (spooled, _, _) = spooled(2);
+
+        let sent = spooled.handle_logs(vec![log("first")]).await;
+        let retried = spooled.flush_expired().await;
+        let failed = match spooled.flush_expired().await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e,
+        };
+
+        assert!(sent.is_ok() && retried.is_ok());
+        assert_eq!(failed.rejected.len(), 1);
+        assert!(failed.logs.is_empty());
+        assert!(spooled.spool.is_empty().unwrap());
+    }
+
+    #[tokio::test]
+    async fn returns_failing_logs_when_draining() {
+        let (spooled, _, _) = spooled(5);
+
+        let sent = spooled.handle_logs(vec![log("first")]).await;
+        let failed = match spooled.flush().await {
+            Ok(_) => panic!("Expected failed logs"),
+            Err(e) => e,
+        };
+
+        assert!(sent.is_ok());
+        assert_eq!(failed.logs.len(), 1);
+        assert!(failed.rejected.is_empty());
+        assert!(spooled.spool.is_empty().unwrap());
+    }
+}
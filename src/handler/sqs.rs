@@ -0,0 +1,355 @@
+use crate::handler::{
+    aws_error, get_required, is_retryable, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
+use crate::models::Log;
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use rusoto_core::Region;
+use rusoto_sqs::{SendMessageBatchRequest, SendMessageBatchRequestEntry, Sqs as SqsApi, SqsClient};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+const MAX_BATCH_MESSAGES: usize = 10;
+const MAX_BATCH_BYTES: usize = 256000; //256KB per batch, give ourselves some overhead.
+const MAX_MESSAGE_BYTES: usize = 262144;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageGroup {
+    RequestId,
+    FunctionName,
+}
+
+impl TryFrom<&str> for MessageGroup {
+    type Error = anyhow::Error;
+    fn try_from(group: &str) -> Result<Self> {
+        match group {
+            "request_id" => Ok(MessageGroup::RequestId),
+            "function_name" => Ok(MessageGroup::FunctionName),
+            _ => Err(Error::msg(format!(
+                "Unable to parse {} as MessageGroup",
+                group
+            ))),
+        }
+    }
+}
+
+pub struct Sqs {
+    queue_url: String,
+    /// Set for FIFO queues, which require every message to have a group.
+    message_group: Option<MessageGroup>,
+    function_name: String,
+    /// Derives deduplication ids from the message body instead of giving every log its own.
+    content_deduplication: bool,
+    /// Deduplication ids of logs that failed with a retryable error, by message body, so the
+    /// retried copy is deduplicated against one that may already have been queued.
+    retried_ids: Mutex<HashMap<String, Vec<String>>>,
+    client: SqsClient,
+}
+
+pub fn from_env() -> Result<Sqs> {
+    let queue_url = get_required("WOODCHUCK_SQS_QUEUE_URL")?;
+    let message_group = match std::env::var("WOODCHUCK_SQS_MESSAGE_GROUP") {
+        Ok(group) => MessageGroup::try_from(group.as_str())?,
+        Err(_) => MessageGroup::FunctionName,
+    };
+    let sqs = Sqs::new(queue_url, message_group);
+    match std::env::var("WOODCHUCK_SQS_CONTENT_DEDUPLICATION").as_deref() == Ok("true") {
+        true => Ok(sqs.with_content_deduplication()),
+        false => Ok(sqs),
+    }
+}
+
+impl Sqs {
+    /// The message group is only used when the queue url is for a FIFO queue.
+    pub fn new(queue_url: String, message_group: MessageGroup) -> Self {
+        Sqs {
+            message_group: match queue_url.ends_with(".fifo") {
+                true => Some(message_group),
+                false => None,
+            },
+            queue_url,
+            function_name: std::env::var("AWS_LAMBDA_FUNCTION_NAME")
+                .unwrap_or_else(|_| "woodchuck".to_string()),
+            content_deduplication: false,
+            retried_ids: Mutex::new(HashMap::new()),
+            client: SqsClient::new(Region::default()),
+        }
+    }
+
+    /// Identical messages within the deduplication interval are then only delivered once.
+    pub fn with_content_deduplication(mut self) -> Self {
+        self.content_deduplication = true;
+        self
+    }
+
+    fn message_group_id(&self, log: &Log) -> Option<String> {
+        self.message_group.as_ref().map(|group| {
+            let request_id = match group {
+                MessageGroup::RequestId => log.request_id(),
+                MessageGroup::FunctionName => None,
+            };
+            request_id.unwrap_or_else(|| self.function_name.clone())
+        })
+    }
+
+    /// FIFO deduplication ids are unique to each log, a log that is retried takes back the id
+    /// it was first sent with.
+    fn deduplication_ids(&self, logs: &[Log]) -> Vec<Option<String>> {
+        if self.message_group.is_none() {
+            return vec![None; logs.len()];
+        }
+        if self.content_deduplication {
+            return logs
+                .iter()
+                .map(|log| Some(hex::encode(Sha256::digest(log.to_string().as_bytes()))))
+                .collect();
+        }
+        let mut retried_ids = self.retried_ids.lock().unwrap();
+        logs.iter()
+            .map(|log| {
+                let body = log.to_string();
+                let id = retried_ids.get_mut(&body).and_then(|ids| ids.pop());
+                if retried_ids.get(&body).is_some_and(|ids| ids.is_empty()) {
+                    retried_ids.remove(&body);
+                }
+                Some(id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()))
+            })
+            .collect()
+    }
+
+    /// Keeps the deduplication id of a log that will be retried.
+    fn hold_id(&self, log: &Log, id: &Option<String>) {
+        if let (Some(id), false) = (id, self.content_deduplication) {
+            self.retried_ids
+                .lock()
+                .unwrap()
+                .entry(log.to_string())
+                .or_default()
+                .push(id.clone());
+        }
+    }
+
+    fn entries(&self, logs: &[Log], ids: &[Option<String>]) -> Vec<SendMessageBatchRequestEntry> {
+        logs.iter()
+            .zip(ids.iter())
+            .enumerate()
+            .map(|(index, (log, id))| SendMessageBatchRequestEntry {
+                id: index.to_string(),
+                message_deduplication_id: id.clone(),
+                message_body: log.to_string(),
+                message_group_id: self.message_group_id(log),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Returns the logs whose entries failed, the rest were queued. Entries that failed through
+    /// the sender's fault, such as an invalid message, are not worth retrying.
+    async fn send_logs(
+        &self,
+        logs: &[Log],
+        ids: &[Option<String>],
+    ) -> Result<FailedToSendLogsError> {
+        let request = SendMessageBatchRequest {
+            queue_url: self.queue_url.clone(),
+            entries: self.entries(logs, ids),
+        };
+
+        // Every modelled error describes a malformed batch.
+        let result = self
+            .client
+            .send_message_batch(request)
+            .await
+            .map_err(|e| aws_error(e, |_| true))?;
+
+        let mut failed = FailedToSendLogsError::default();
+        for failure in result.failed.iter() {
+            log::error!(
+                "{}: {:?} (sender fault: {})",
+                failure.code,
+                failure.message,
+                failure.sender_fault
+            );
+            let index = match failure.id.parse::<usize>().ok().filter(|&i| i < logs.len()) {
+                Some(index) => index,
+                None => return Err(Error::msg(format!("Unknown Entry Id {}", failure.id))),
+            };
+            match failure.sender_fault {
+                true => failed.rejected.push(logs[index].clone()),
+                false => {
+                    self.hold_id(&logs[index], &ids[index]);
+                    failed.logs.push(logs[index].clone());
+                }
+            }
+        }
+        Ok(failed)
+    }
+}
+
+#[async_trait]
+impl LogHandler for Sqs {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        // A single message over the limit would fail the whole batch, so reject it alone.
+        let (mut local_logs, oversized): (Vec<Log>, Vec<Log>) = logs
+            .into_iter()
+            .partition(|log| log.to_string().len() <= MAX_MESSAGE_BYTES);
+        if !oversized.is_empty() {
+            log::error!(
+                "Rejecting {} logs larger than {} bytes",
+                oversized.len(),
+                MAX_MESSAGE_BYTES
+            );
+            failed_to_send_logs.reason = Some(format!("Larger than {} bytes", MAX_MESSAGE_BYTES));
+            failed_to_send_logs.rejected.extend(oversized);
+        }
+
+        let chunks = local_logs
+            .byte_chunks_safe_mut(MAX_BATCH_BYTES)
+            .flat_map(|chunk| chunk.chunks(MAX_BATCH_MESSAGES));
+
+        for (index, chunk) in chunks.enumerate() {
+            let ids = self.deduplication_ids(chunk);
+            let rslt = self.send_logs(chunk, &ids).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    if is_retryable(&e) {
+                        for (log, id) in chunk.iter().zip(ids.iter()) {
+                            self.hold_id(log, id);
+                        }
+                    }
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                Ok(failed) => {
+                    log::debug!(
+                        "Sent Chunk {} with {} items, {} failed.",
+                        index,
+                        chunk.len(),
+                        failed.len()
+                    );
+                    failed_to_send_logs.extend(failed);
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageGroup, Sqs, MAX_MESSAGE_BYTES};
+    use crate::handler::LogHandler;
+    use crate::models::{Log, StructuredLog};
+
+    fn fifo() -> Sqs {
+        Sqs::new(
+            "https://sqs.us-east-1.amazonaws.com/123456789012/logs.fifo".to_string(),
+            MessageGroup::RequestId,
+        )
+    }
+
+    #[test]
+    fn groups_fifo_messages_by_request_id() {
+        let sqs = fifo();
+        let logs = vec![
+            Log::Unformatted(StructuredLog {
+                timestamp: None,
+                guid: Some("6e48723a-1596-4313-a9af-e4da9214d637".to_string()),
+                level: None,
+                data: serde_json::Value::String("Hello World".to_string()),
+                trace_id: None,
+                span_id: None,
+            }),
+            Log::Formatted(serde_json::json!({
+                "requestId": "79b4f56e-95b1-4643-9700-2807f4e68189",
+                "message": "Hello World"
+            })),
+        ];
+
+        let entries = sqs.entries(&logs, &sqs.deduplication_ids(&logs));
+
+        assert_eq!(entries[0].id, "0");
+        assert_eq!(
+            entries[0].message_group_id.as_deref(),
+            Some("6e48723a-1596-4313-a9af-e4da9214d637")
+        );
+        assert_eq!(
+            entries[1].message_group_id.as_deref(),
+            Some("79b4f56e-95b1-4643-9700-2807f4e68189")
+        );
+    }
+
+    #[test]
+    fn reuses_deduplication_ids_of_retried_logs() {
+        let sqs = fifo();
+        let logs = vec![
+            Log::Formatted(serde_json::Value::String("Hello World".to_string())),
+            Log::Formatted(serde_json::Value::String("Hello World".to_string())),
+        ];
+
+        let ids = sqs.deduplication_ids(&logs);
+        sqs.hold_id(&logs[1], &ids[1]);
+        let retried = sqs.deduplication_ids(&logs[1..]);
+
+        assert!(ids[0].is_some() && ids[1].is_some());
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(retried[0], ids[1]);
+        assert_ne!(sqs.deduplication_ids(&logs[1..])[0], ids[1]);
+    }
+
+    #[test]
+    fn hashes_bodies_for_content_deduplication() {
+        let sqs = fifo().with_content_deduplication();
+        let logs = vec![
+            Log::Formatted(serde_json::Value::String("Hello World".to_string())),
+            Log::Formatted(serde_json::Value::String("Hello World".to_string())),
+        ];
+
+        let ids = sqs.deduplication_ids(&logs);
+
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(ids[0].as_ref().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn leaves_standard_queues_without_deduplication_ids() {
+        let sqs = Sqs::new(
+            "https://sqs.us-east-1.amazonaws.com/123456789012/logs".to_string(),
+            MessageGroup::RequestId,
+        );
+        let logs = vec![Log::Formatted(serde_json::Value::String(
+            "Hello World".to_string(),
+        ))];
+
+        let entries = sqs.entries(&logs, &sqs.deduplication_ids(&logs));
+
+        assert!(entries[0].message_deduplication_id.is_none());
+        assert!(entries[0].message_group_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_messages() {
+        let sqs = Sqs::new(
+            "https://sqs.us-east-1.amazonaws.com/123456789012/logs".to_string(),
+            MessageGroup::FunctionName,
+        );
+        let logs = vec![Log::Formatted(serde_json::Value::String(
+            "x".repeat(MAX_MESSAGE_BYTES),
+        ))];
+
+        let failed = match sqs.handle_logs(logs).await {
+            Ok(_) => panic!("Expected rejected logs"),
+            Err(e) => e,
+        };
+
+        assert_eq!(failed.rejected.len(), 1);
+        assert!(failed.logs.is_empty());
+    }
+}
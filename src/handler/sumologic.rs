@@ -0,0 +1,239 @@
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse, StatusError,
+};
+use crate::models::Log;
+use anyhow::{ensure, Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
+use reqwest::Client;
+use std::io::Write;
+use std::time::Duration;
+
+const MAX_PAYLOAD_BYTES: usize = 1000000; //Sumo recommends requests of up to 1MB uncompressed.
+
+#[derive(Debug, Clone)]
+pub struct SumoLogic {
+    url: String,
+    client: Client,
+}
+
+/// Joins fields into the `key=value,key=value` form of `X-Sumo-Fields`.
+fn fields_header(fields: &[(String, String)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+pub fn from_env() -> Result<SumoLogic> {
+    let mut builder = SumoLogic::builder()
+        .with_url(get_required("SUMO_URL")?)
+        .with_timeout(get_timeout("SUMO_TIMEOUT"));
+    if let Ok(category) = std::env::var("SUMO_CATEGORY") {
+        builder = builder.with_category(category);
+    }
+    if let Ok(name) =
+        std::env::var("SUMO_NAME").or_else(|_| std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+    {
+        builder = builder.with_name(name);
+    }
+    if let Ok(host) = std::env::var("SUMO_HOST").or_else(|_| std::env::var("AWS_REGION")) {
+        builder = builder.with_host(host);
+    }
+    for (key, var) in [
+        ("function", "AWS_LAMBDA_FUNCTION_NAME"),
+        ("version", "AWS_LAMBDA_FUNCTION_VERSION"),
+        ("region", "AWS_REGION"),
+    ]
+    .iter()
+    {
+        if let Ok(value) = std::env::var(var) {
+            builder = builder.with_field(key.to_string(), value);
+        }
+    }
+    if let Ok(fields) = std::env::var("SUMO_FIELDS") {
+        for field in fields.split(',').filter(|f| !f.trim().is_empty()) {
+            match field.split_once('=') {
+                Some((key, value)) => {
+                    builder = builder.with_field(key.trim().to_string(), value.trim().to_string())
+                }
+                None => return Err(Error::msg(format!("Unable to parse {} as Field", field))),
+            }
+        }
+    }
+    builder.build()
+}
+
+impl SumoLogic {
+    pub fn builder() -> SumoLogicBuilder {
+        SumoLogicBuilder::new()
+    }
+
+    fn payload(&self, logs: &[Log]) -> Result<Vec<u8>> {
+        let payload = logs
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload.as_bytes())?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Sends the logs, a 429 Too Many Requests is left to the retry policy to back off from
+    /// rather than retried here, so throttled logs are not retried twice over.
+    async fn send_logs(&self, logs: &[Log]) -> Result<()> {
+        let payload = self.payload(logs)?;
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let res = self.client.post(&self.url).body(payload).send().await?;
+
+        println!("Response: Status:{}", &res.status());
+
+        ensure!(res.status().is_success(), StatusError(res.status()));
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogHandler for SumoLogic {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut local_logs = logs.to_owned();
+        let chunks = local_logs.byte_chunks_safe_mut(MAX_PAYLOAD_BYTES);
+
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        for (index, chunk) in chunks.enumerate() {
+            let rslt = self.send_logs(chunk).await;
+            match rslt {
+                Err(e) => {
+                    log::debug!("Failed sending Chunk {} with {} items.", index, chunk.len());
+                    failed_to_send_logs.add(chunk, &e);
+                    log::error!("{}", e)
+                }
+                _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct SumoLogicBuilder {
+    url: Option<String>,
+    category: Option<String>,
+    name: Option<String>,
+    host: Option<String>,
+    fields: Vec<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl SumoLogicBuilder {
+    pub fn new() -> Self {
+        SumoLogicBuilder {
+            url: None,
+            category: None,
+            name: None,
+            host: None,
+            fields: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// The HTTP source url, which includes the collector token.
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_category(mut self, category: String) -> Self {
+        self.category = Some(category);
+        self
+    }
+
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Adds a field to `X-Sumo-Fields`, replacing any earlier field with the same key.
+    pub fn with_field(mut self, key: String, value: String) -> Self {
+        self.fields.retain(|(k, _)| *k != key);
+        self.fields.push((key, value));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<SumoLogic> {
+        match self {
+            Self { url: Some(url), .. } => {
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                for (name, value) in [
+                    ("X-Sumo-Category", &self.category),
+                    ("X-Sumo-Name", &self.name),
+                    ("X-Sumo-Host", &self.host),
+                ]
+                .iter()
+                {
+                    if let Some(value) = value {
+                        headers.insert(*name, HeaderValue::from_str(value)?);
+                    }
+                }
+                if !self.fields.is_empty() {
+                    headers.insert(
+                        "X-Sumo-Fields",
+                        HeaderValue::from_str(&fields_header(&self.fields))?,
+                    );
+                }
+
+                let builder = Client::builder().default_headers(headers);
+                let client = match self.timeout {
+                    Some(duration) => builder.timeout(duration).build()?,
+                    None => builder.build()?,
+                };
+
+                Ok(SumoLogic { url, client })
+            }
+            Self { url: None, .. } => Err(Error::msg("Url Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SumoLogic;
+
+    #[test]
+    fn replaces_fields() {
+        let builder = SumoLogic::builder()
+            .with_field("region".to_string(), "us-east-1".to_string())
+            .with_field("team".to_string(), "payments".to_string())
+            .with_field("region".to_string(), "eu-west-1".to_string());
+
+        assert_eq!(
+            super::fields_header(&builder.fields),
+            "team=payments,region=eu-west-1"
+        );
+    }
+}
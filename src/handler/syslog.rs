@@ -0,0 +1,435 @@
+use crate::handler::connection::{connect, Connection};
+use crate::handler::{
+    get_required, get_timeout, FailedToSendLogsError, LogHandler, LogHandlerResponse,
+};
+use crate::models::{Log, LogLevel};
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use byte_chunk::SafeByteChunkedMut;
+use chrono::{SecondsFormat, Utc};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+const NIL: &str = "-";
+const DEFAULT_FACILITY: u8 = 1; //user-level messages
+const DEFAULT_SD_ID: &str = "woodchuck@32473";
+const MAX_UDP_MESSAGE: usize = 65000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl TryFrom<&str> for Protocol {
+    type Error = anyhow::Error;
+    fn try_from(protocol: &str) -> Result<Self> {
+        match protocol {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            "tls" => Ok(Protocol::Tls),
+            _ => Err(Error::msg(format!(
+                "Unable to parse {} as Protocol",
+                protocol
+            ))),
+        }
+    }
+}
+
+pub fn parse_facility(facility: &str) -> Result<u8> {
+    let names = [
+        "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+        "authpriv", "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2",
+        "local3", "local4", "local5", "local6", "local7",
+    ];
+    match facility.parse::<u8>() {
+        Ok(code) if (code as usize) < names.len() => Ok(code),
+        _ => names
+            .iter()
+            .position(|name| *name == facility)
+            .map(|code| code as u8)
+            .ok_or_else(|| Error::msg(format!("Unable to parse {} as Facility", facility))),
+    }
+}
+
+fn severity(level: Option<LogLevel>) -> u8 {
+    match level {
+        Some(LogLevel::Critical) => 2,
+        Some(LogLevel::Error) => 3,
+        Some(LogLevel::Warn) => 4,
+        Some(LogLevel::Info) | None => 6,
+        Some(LogLevel::Debug) | Some(LogLevel::Trace) => 7,
+    }
+}
+
+/// Keeps printable US-ASCII, as required for HOSTNAME, APP-NAME, PROCID and PARAM-NAME.
+fn header_field(value: &str, max: usize, exclude: &[char]) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !exclude.contains(c))
+        .take(max)
+        .collect();
+    match field.len() {
+        0 => NIL.to_string(),
+        _ => field,
+    }
+}
+
+fn escape_param_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+pub struct Syslog {
+    host: String,
+    port: u16,
+    protocol: Protocol,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    sd_id: String,
+    sd_fields: Option<Vec<String>>,
+    timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+
+pub fn from_env() -> Result<Syslog> {
+    let mut builder = Syslog::builder()
+        .with_host(get_required("SYSLOG_HOST")?)
+        .with_timeout(get_timeout("SYSLOG_TIMEOUT"));
+    if let Ok(protocol) = std::env::var("SYSLOG_PROTOCOL") {
+        builder = builder.with_protocol(Protocol::try_from(protocol.to_lowercase().as_str())?);
+    }
+    if let Ok(port) = std::env::var("SYSLOG_PORT") {
+        builder = builder.with_port(port.parse()?);
+    }
+    if let Ok(facility) = std::env::var("SYSLOG_FACILITY") {
+        builder = builder.with_facility(parse_facility(&facility)?);
+    }
+    if let Ok(hostname) = std::env::var("SYSLOG_HOSTNAME") {
+        builder = builder.with_hostname(hostname);
+    }
+    if let Ok(app_name) =
+        std::env::var("SYSLOG_APP_NAME").or_else(|_| std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+    {
+        builder = builder.with_app_name(app_name);
+    }
+    if let Ok(sd_id) = std::env::var("SYSLOG_SD_ID") {
+        builder = builder.with_sd_id(sd_id);
+    }
+    if let Ok(fields) = std::env::var("SYSLOG_SD_FIELDS") {
+        builder = builder.with_sd_fields(fields.split(',').map(|f| f.trim().to_string()).collect());
+    }
+    builder.build()
+}
+
+impl Syslog {
+    pub fn builder() -> SyslogBuilder {
+        SyslogBuilder::new()
+    }
+
+    fn structured_data(&self, log: &Log) -> String {
+        let data = match log {
+            Log::Formatted(Value::Object(data)) => data,
+            _ => return NIL.to_string(),
+        };
+        let params: Vec<String> = data
+            .iter()
+            .filter(|(name, _)| match &self.sd_fields {
+                Some(fields) => fields.contains(*name),
+                None => true,
+            })
+            .filter_map(|(name, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return None,
+                };
+                Some(format!(
+                    "{}=\"{}\"",
+                    header_field(name, 32, &['=', ']', '"']),
+                    escape_param_value(&value)
+                ))
+            })
+            .collect();
+        match params.len() {
+            0 => NIL.to_string(),
+            _ => format!("[{} {}]", self.sd_id, params.join(" ")),
+        }
+    }
+
+    /// Formats a log as an RFC 5424 message.
+    fn format(&self, log: &Log) -> String {
+        let pri = self.facility * 8 + severity(log.level());
+        let timestamp = log
+            .timestamp()
+            .unwrap_or_else(Utc::now)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        let procid = match log {
+            Log::Unformatted(data) => data.guid.as_deref().unwrap_or(NIL),
+            _ => NIL,
+        };
+        let message = match log {
+            Log::Unformatted(data) => match &data.data {
+                Value::String(s) => s.trim_end().to_string(),
+                other => other.to_string(),
+            },
+            Log::Formatted(data) => data.to_string(),
+        };
+        format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            pri,
+            timestamp,
+            self.hostname,
+            self.app_name,
+            header_field(procid, 128, &[]),
+            NIL,
+            self.structured_data(log),
+            message
+        )
+    }
+
+    async fn send_udp(&self, logs: &[Log]) -> Vec<Log> {
+        let socket = match UdpSocket::bind(("0.0.0.0", 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("{}", e);
+                return logs.to_vec();
+            }
+        };
+        if let Err(e) = socket.connect((self.host.as_str(), self.port)).await {
+            log::error!("{}", e);
+            return logs.to_vec();
+        }
+
+        let mut failed_to_send_logs = Vec::<Log>::new();
+        for log in logs.iter() {
+            let mut message = self.format(log).into_bytes();
+            message.truncate(MAX_UDP_MESSAGE);
+            if let Err(e) = socket.send(&message).await {
+                log::error!("{}", e);
+                failed_to_send_logs.push(log.clone());
+            }
+        }
+        failed_to_send_logs
+    }
+
+    /// Writes the logs using octet-counting framing, reusing the connection between calls.
+    async fn send_stream(&self, logs: &[Log]) -> Result<()> {
+        let mut payload = Vec::new();
+        for log in logs.iter() {
+            let message = self.format(log);
+            payload.extend_from_slice(format!("{} ", message.len()).as_bytes());
+            payload.extend_from_slice(message.as_bytes());
+        }
+
+        log::debug!(
+            "Sending {} logs, payload length: {}",
+            &logs.len(),
+            &payload.len()
+        );
+
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(
+                connect(
+                    &self.host,
+                    self.port,
+                    self.protocol == Protocol::Tls,
+                    self.timeout,
+                )
+                .await?,
+            );
+        }
+        let stream = connection.as_mut().unwrap();
+        let rslt = tokio::time::timeout(self.timeout, async {
+            stream.write_all(&payload).await?;
+            stream.flush().await
+        })
+        .await;
+        match rslt {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                *connection = None;
+                Err(e.into())
+            }
+            Err(e) => {
+                *connection = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LogHandler for Syslog {
+    async fn handle_logs(&self, logs: Vec<Log>) -> LogHandlerResponse {
+        let mut failed_to_send_logs = FailedToSendLogsError::default();
+
+        match self.protocol {
+            Protocol::Udp => failed_to_send_logs.logs.extend(self.send_udp(&logs).await),
+            _ => {
+                let mut local_logs = logs.to_owned();
+                let chunks = local_logs.byte_chunks_safe_mut(1000000);
+
+                for (index, chunk) in chunks.enumerate() {
+                    let rslt = self.send_stream(chunk).await;
+                    match rslt {
+                        Err(e) => {
+                            log::debug!(
+                                "Failed sending Chunk {} with {} items.",
+                                index,
+                                chunk.len()
+                            );
+                            failed_to_send_logs.add(chunk, &e);
+                            log::error!("{}", e)
+                        }
+                        _ => log::debug!("Sent Chunk {} with {} items.", index, chunk.len()),
+                    }
+                }
+            }
+        }
+
+        failed_to_send_logs.into_response()
+    }
+}
+
+pub struct SyslogBuilder {
+    host: Option<String>,
+    port: Option<u16>,
+    protocol: Protocol,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    sd_id: String,
+    sd_fields: Option<Vec<String>>,
+    timeout: Option<Duration>,
+}
+
+impl SyslogBuilder {
+    pub fn new() -> Self {
+        SyslogBuilder {
+            host: None,
+            port: None,
+            protocol: Protocol::Udp,
+            facility: DEFAULT_FACILITY,
+            hostname: NIL.to_string(),
+            app_name: NIL.to_string(),
+            sd_id: DEFAULT_SD_ID.to_string(),
+            sd_fields: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_host(mut self, host: String) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_facility(mut self, facility: u8) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: String) -> Self {
+        self.hostname = hostname;
+        self
+    }
+
+    pub fn with_app_name(mut self, app_name: String) -> Self {
+        self.app_name = app_name;
+        self
+    }
+
+    pub fn with_sd_id(mut self, sd_id: String) -> Self {
+        self.sd_id = sd_id;
+        self
+    }
+
+    /// Only these JSON fields are written as structured data, by default every scalar field is.
+    pub fn with_sd_fields(mut self, sd_fields: Vec<String>) -> Self {
+        self.sd_fields = Some(sd_fields);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.timeout = timeout.map(Duration::from_millis);
+        self
+    }
+
+    pub fn build(self) -> Result<Syslog> {
+        match self {
+            Self {
+                host: Some(host), ..
+            } => {
+                let port = match (self.port, &self.protocol) {
+                    (Some(port), _) => port,
+                    (None, Protocol::Tls) => 6514,
+                    (None, _) => 514,
+                };
+
+                Ok(Syslog {
+                    host,
+                    port,
+                    protocol: self.protocol,
+                    facility: self.facility,
+                    hostname: header_field(&self.hostname, 255, &[]),
+                    app_name: header_field(&self.app_name, 48, &[]),
+                    sd_id: header_field(&self.sd_id, 32, &['=', ']', '"']),
+                    sd_fields: self.sd_fields,
+                    timeout: self.timeout.unwrap_or(Duration::from_secs(60)),
+                    connection: Mutex::new(None),
+                })
+            }
+            Self { host: None, .. } => Err(Error::msg("Host Required")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_facility, Syslog};
+    use crate::models::Log;
+
+    #[test]
+    fn formats_rfc5424_message() {
+        let syslog = Syslog::builder()
+            .with_host("localhost".to_string())
+            .with_facility(parse_facility("local0").unwrap())
+            .with_app_name("my-function".to_string())
+            .with_sd_fields(vec!["service".to_string()])
+            .build()
+            .unwrap();
+        let log = Log::Formatted(serde_json::json!({
+            "level": "error",
+            "service": "billing \"eu\"",
+            "timestamp": "2020-11-18T23:52:30.128Z"
+        }));
+
+        assert_eq!(
+            syslog.format(&log),
+            format!(
+                "<131>1 2020-11-18T23:52:30.128Z - my-function - - [woodchuck@32473 service=\"billing \\\"eu\\\"\"] {}",
+                log.to_string()
+            )
+        );
+    }
+}
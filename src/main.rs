@@ -9,8 +9,10 @@ mod models;
 mod parser;
 
 use anyhow::Result;
-use extension::{logs_api, runtime};
+use extension::{flusher, logs_api, retry, runtime};
 use reqwest::Client;
+use std::sync::Arc;
+use tokio::sync::Notify;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -20,19 +22,42 @@ async fn main() -> Result<()> {
     log::debug!("Built Client");
     let log_queue = models::new_log_queue();
     let log_dest = handler::get_default()?;
+    let dead_letter = handler::get_dead_letter()?;
     let log_config = logs_api::LogSubscriptionConfig::default();
+    let retry_policy = retry::RetryPolicy::default();
+    let flush_signal = Arc::new(Notify::new());
 
     log::debug!("Registering Extension...");
     let ext_id = extension::register_extension(&client).await?;
     log::debug!("Registered.");
 
     log::debug!("Starting Log Server...");
-    logs_api::start_log_server(&log_config, log_queue.clone()); //We need to start running our server before we register as a log extension
+    logs_api::start_log_server(&log_config, log_queue.clone(), flush_signal.clone()); //We need to start running our server before we register as a log extension
     log::debug!("Started Log Server.");
     log::debug!("Registering Log Server");
     logs_api::subscribe(&log_config, &client, &ext_id).await;
     log::debug!("Registered.");
+    log::debug!("Starting Flusher...");
+    let flusher = flusher::start_flusher(
+        flusher::FlushConfig::default(),
+        log_queue.clone(),
+        log_dest.clone(),
+        retry_policy.clone(),
+        dead_letter.clone(),
+        flush_signal.clone(),
+    );
     log::debug!("Starting Runtime Consumer...");
-    let response = runtime::run(&client, ext_id, log_queue, log_dest).await;
+    let response = runtime::run(
+        &client,
+        ext_id,
+        log_queue,
+        log_dest,
+        retry_policy,
+        log_config.buffer_timeout(),
+        flush_signal,
+        flusher,
+        dead_letter,
+    )
+    .await;
     response
 }
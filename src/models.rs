@@ -1,11 +1,19 @@
 use anyhow::{Error, Result};
 use byte_chunk::SizeInBytes;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::convert::TryFrom;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+const REQUEST_ID_FIELDS: [&str; 4] = [
+    "requestId",
+    "request_id",
+    "awsRequestId",
+    "function_request_id",
+];
+
 pub type LogQueue = Arc<RwLock<Vec<Log>>>;
 
 pub fn new_log_queue() -> LogQueue {
@@ -19,27 +27,31 @@ pub struct RawCloudWatchLog {
     pub record: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StructuredLog {
     pub timestamp: Option<String>,
     pub guid: Option<String>,
     pub level: Option<LogLevel>,
     pub data: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum LogLevel {
-    #[serde(rename(serialize = "INFO"))]
+    #[serde(rename = "INFO")]
     Info,
-    #[serde(rename(serialize = "WARN"))]
+    #[serde(rename = "WARN")]
     Warn,
-    #[serde(rename(serialize = "ERROR"))]
+    #[serde(rename = "ERROR")]
     Error,
-    #[serde(rename(serialize = "TRACE"))]
+    #[serde(rename = "TRACE")]
     Trace,
-    #[serde(rename(serialize = "CRITICAL"))]
+    #[serde(rename = "CRITICAL")]
     Critical,
-    #[serde(rename(serialize = "DEBUG"))]
+    #[serde(rename = "DEBUG")]
     Debug,
 }
 
@@ -58,12 +70,89 @@ impl TryFrom<String> for LogLevel {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Log {
     Unformatted(StructuredLog),
     Formatted(serde_json::Value),
 }
 
+impl Log {
+    pub fn level(&self) -> Option<LogLevel> {
+        match self {
+            Log::Unformatted(log) => log.level.clone(),
+            Log::Formatted(data) => match &data["level"] {
+                Value::String(level) => LogLevel::try_from(level.to_uppercase()).ok(),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        let timestamp = match self {
+            Log::Unformatted(log) => log.timestamp.as_deref(),
+            Log::Formatted(data) => data["timestamp"].as_str(),
+        }?;
+        DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+    }
+
+    /// The Lambda request id, either extracted by the parser or taken from the `requestId`,
+    /// `request_id`, `awsRequestId` or `function_request_id` field of a JSON log.
+    pub fn request_id(&self) -> Option<String> {
+        match self {
+            Log::Unformatted(log) => log.guid.clone(),
+            Log::Formatted(data) => REQUEST_ID_FIELDS
+                .iter()
+                .find_map(|field| data[field].as_str())
+                .map(|id| id.to_string()),
+        }
+    }
+
+    /// The timestamp in nanoseconds since the Unix epoch, `None` when it is missing or outside
+    /// the 1970 to 2262 range nanoseconds can count.
+    pub fn timestamp_nanos(&self) -> Option<u64> {
+        let timestamp = self.timestamp()?;
+        u64::try_from(timestamp.timestamp())
+            .ok()?
+            .checked_mul(1_000_000_000)?
+            .checked_add(timestamp.timestamp_subsec_nanos() as u64)
+            .filter(|nanos| *nanos <= i64::MAX as u64)
+    }
+
+    /// The trace and span ids the log was written under, either extracted by the parser or
+    /// taken from the `dd.trace_id`/`dd.span_id` or `trace_id`/`span_id` fields of a JSON log.
+    pub fn trace_context(&self) -> Option<(String, String)> {
+        fn id(value: &Value) -> Option<String> {
+            match value {
+                Value::String(id) => Some(id.clone()),
+                Value::Number(id) => Some(id.to_string()),
+                _ => None,
+            }
+        }
+        match self {
+            Log::Unformatted(log) => match (&log.trace_id, &log.span_id) {
+                (Some(trace_id), Some(span_id)) => Some((trace_id.clone(), span_id.clone())),
+                _ => None,
+            },
+            Log::Formatted(data) => match (id(&data["dd"]["trace_id"]), id(&data["dd"]["span_id"])) {
+                (Some(trace_id), Some(span_id)) => Some((trace_id, span_id)),
+                _ => match (id(&data["trace_id"]), id(&data["span_id"])) {
+                    (Some(trace_id), Some(span_id)) => Some((trace_id, span_id)),
+                    _ => None,
+                },
+            },
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Log::Unformatted(log) => log.data.get(name),
+            Log::Formatted(data) => data.get(name),
+        }
+    }
+}
+
 impl ToString for Log {
     fn to_string(&self) -> String {
         match self {
@@ -37,6 +37,8 @@ impl Into<StructuredLog> for DotnetSixCloudWatchLog {
                 Ok(value) => value,
                 Err(_) => serde_json::to_value(&self.data).unwrap(),
             },
+            trace_id: None,
+            span_id: None,
         }
     }
 }
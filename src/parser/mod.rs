@@ -11,6 +11,7 @@ pub fn parse(logs: Vec<RawCloudWatchLog>) -> Vec<Log> {
     logs.into_iter()
         .filter(|log| match log.r#type.as_str() {
             "function" => true,
+            platform if platform.starts_with("platform.") => false,
             _ => {
                 println!("{:?}", log);
                 false
@@ -10,7 +10,7 @@ use serde::Deserialize;
     \s+
     (?P<level>(INFO)|(WARN)|(ERROR))
     \s+
-    (\[(dd\.trace_id=\d+)\s+(dd\.span_id=\d+)\])?
+    (\[dd\.trace_id=(?P<trace_id>\d+)\s+dd\.span_id=(?P<span_id>\d+)\])?
     \s*
     (?P<data>(?s).*)
   "#)]
@@ -19,6 +19,8 @@ struct NodeCloudWatchLog {
     guid: String,
     level: String,
     data: String,
+    trace_id: Option<String>,
+    span_id: Option<String>,
 }
 
 impl Into<StructuredLog> for NodeCloudWatchLog {
@@ -36,6 +38,8 @@ impl Into<StructuredLog> for NodeCloudWatchLog {
                 Ok(value) => value,
                 Err(_) => serde_json::to_value(&self.data).unwrap(),
             },
+            trace_id: self.trace_id,
+            span_id: self.span_id,
         }
     }
 }
@@ -47,7 +51,13 @@ pub fn parse(log: &RawCloudWatchLog) -> Option<Log> {
                 Ok(l) => {
                     let structured_log: StructuredLog = l.into();
                     match structured_log.data {
-                        serde_json::Value::Object(_) => Some(Log::Formatted(structured_log.data)),
+                        serde_json::Value::Object(mut data) => {
+                            // Kept under `dd`, where `Log::trace_context` finds them for OTLP and New Relic.
+                            if let (Some(trace_id), Some(span_id)) = (structured_log.trace_id, structured_log.span_id) {
+                                data.entry("dd").or_insert(serde_json::json!({ "trace_id": trace_id, "span_id": span_id }));
+                            }
+                            Some(Log::Formatted(serde_json::Value::Object(data)))
+                        },
                         _ => Some(Log::Unformatted(structured_log)),
                     }
                 },
@@ -137,9 +147,13 @@ mod tests {
 
         println!("{}", l.to_string());
 
+        assert_eq!(l.trace_context(), Some(("8698380355092788351".to_string(), "8698380355092788351".to_string())));
+
         match l {
             Log::Formatted(log) => {
                 assert_eq!(log["data"], "Hello World");
+                assert_eq!(log["dd"]["trace_id"], "8698380355092788351");
+                assert_eq!(log["dd"]["span_id"], "8698380355092788351");
             },
             _ => {
                 panic!("Expected Preformatted log");
@@ -34,6 +34,8 @@ impl Into<StructuredLog> for PythonCloudWatchLog {
                 Ok(value) => value,
                 Err(_) => serde_json::to_value(&self.data).unwrap(),
             },
+            trace_id: None,
+            span_id: None,
         }
     }
 }